@@ -1,4 +1,9 @@
+use adaptivegrain_rs::cache::LutCache;
+use adaptivegrain_rs::curve::Curve;
+use adaptivegrain_rs::grain::Rng;
+use adaptivegrain_rs::luma::{ColorRange, Transfer};
 use adaptivegrain_rs::mask::*;
+use adaptivegrain_rs::simd::{self, SimdLevel};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
 fn mask_value(c: &mut Criterion) {
@@ -13,5 +18,117 @@ fn mask_value(c: &mut Criterion) {
     });
 }
 
-criterion_group!(mask, mask_value);
+fn lut_cache(c: &mut Criterion) {
+    // Frame averages that drift by 1e-3 per frame like in a regular scene
+    let mut rng = Rng::new(1, 0);
+    let mut average = 0.4;
+    let averages: Vec<f32> = (0..64)
+        .map(|_| {
+            average += rng.next_gaussian() * 0.001;
+            average
+        })
+        .collect();
+    let curve = Curve::default();
+    for &depth in &[10, 16] {
+        let key = LutKey {
+            depth,
+            output_depth: depth,
+            range: ColorRange::Full,
+            transfer: Transfer::Sdr,
+        };
+        let max = ((1u32 << depth) - 1) as f32;
+        let build = |ls| build_lut::<u16>(&curve, MaskRange::default(), key, ls, max);
+        c.bench_function(&format!("{} bit lut per frame", depth), |b| {
+            b.iter(|| {
+                averages.iter().for_each(|&avg| {
+                    black_box(build(calc_luma_scaling(black_box(avg), 10.0)));
+                });
+            })
+        });
+        c.bench_function(&format!("{} bit lut cached", depth), |b| {
+            b.iter(|| {
+                // A new cache for every pass, so the hits are the ones that one pass over the frames gets
+                let cache = LutCache::default();
+                averages.iter().for_each(|&avg| {
+                    let ls = calc_luma_scaling(black_box(avg), 10.0);
+                    black_box(cache.get(key, ls, LUT_CACHE_MAX_ERROR, build));
+                });
+            })
+        });
+    }
+}
+
+fn simd_kernels(c: &mut Criterion) {
+    // One 1080p row
+    let row: Vec<f32> = (0..1920).map(|i| i as f32 / 1919.0).collect();
+    let indices: Vec<u16> = (0..1920).map(|i| (i * 34) as u16).collect();
+    let lut: Vec<u32> = (0..65536).collect();
+    let lut16: Vec<u16> = (0..=65535).collect();
+    let lut8: Vec<u8> = (0..65536).map(|i| (i >> 8) as u8).collect();
+    let mut base = vec![0.0; row.len()];
+    let mut values = vec![0; row.len()];
+    let mut values16 = vec![0; row.len()];
+    let mut values8 = vec![0; row.len()];
+    for &level in &[
+        SimdLevel::Scalar,
+        SimdLevel::Sse2,
+        SimdLevel::Avx2,
+        SimdLevel::Avx512,
+    ] {
+        if level > SimdLevel::detect() {
+            continue;
+        }
+        c.bench_function(&format!("mask base row {:?}", level), |b| {
+            b.iter(|| simd::mask_base(level, black_box(&row), &mut base, true))
+        });
+        c.bench_function(&format!("mask value row {:?}", level), |b| {
+            b.iter(|| {
+                simd::mask_base(level, black_box(&row), &mut base, true);
+                base.iter_mut().for_each(|v| *v = v.powf(1.7));
+            })
+        });
+        c.bench_function(&format!("fast mask value row {:?}", level), |b| {
+            b.iter(|| simd::mask_value_fast(level, black_box(&row), &mut base, true, 1.7))
+        });
+        c.bench_function(&format!("32 bit lut gather row {:?}", level), |b| {
+            b.iter(|| simd::gather(level, black_box(&indices), &lut, 0, &mut values))
+        });
+        c.bench_function(&format!("16 bit lut gather row {:?}", level), |b| {
+            b.iter(|| simd::gather(level, black_box(&indices), &lut16, 0, &mut values16))
+        });
+        c.bench_function(&format!("8 bit lut gather row {:?}", level), |b| {
+            b.iter(|| simd::gather(level, black_box(&indices), &lut8, 0, &mut values8))
+        });
+    }
+}
+
+fn interpolated_lut(c: &mut Criterion) {
+    // One 1080p row of float input, slightly out of range at both ends
+    let row: Vec<f32> = (0..1920).map(|i| i as f32 / 1900.0 - 0.005).collect();
+    let mut values = vec![0.0; row.len()];
+    let ls = black_box(calc_luma_scaling(0.412323, 10.0));
+    let mask_fn = |x: f32| get_mask_value_clamping(x, ls);
+    c.bench_function("interpolated lut per frame", |b| {
+        b.iter(|| black_box(InterpolatedLut::new(mask_fn)))
+    });
+    let lut = InterpolatedLut::new(mask_fn);
+    c.bench_function("interpolated lut row", |b| {
+        b.iter(|| {
+            black_box(&row)
+                .iter()
+                .zip(values.iter_mut())
+                .for_each(|(&x, v)| *v = lut.get(x, mask_fn))
+        })
+    });
+    c.bench_function("direct evaluation row", |b| {
+        b.iter(|| {
+            black_box(&row)
+                .iter()
+                .zip(values.iter_mut())
+                .for_each(|(&x, v)| *v = mask_fn(x))
+        })
+    });
+}
+
+criterion_group!(mask, mask_value, lut_cache, simd_kernels, interpolated_lut);
 criterion_main!(mask);
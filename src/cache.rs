@@ -0,0 +1,224 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Number of LUTs that are kept per cache.
+pub const DEFAULT_CAPACITY: usize = 16;
+
+/// A LUT with its key and the quantized luma scaling it was built for.
+type Entry<K, T> = ((K, i64), Arc<Vec<T>>);
+
+/// Rounds the logarithm of the luma scaling to a step that changes mask values by at most `max_error`.
+/// Every curve is a base b in [0, 1] to the power of the luma scaling l, and
+/// |d(b^(l·e^q))/dq| = |l·ln(b)·b^l| <= 1/e, so rounding q to a multiple of 2e·max_error is enough.
+/// A relative step is used because consecutive frames change the luma scaling by about the same fraction.
+pub fn quantize_luma_scaling(luma_scaling: f32, max_error: f32) -> i64 {
+    if luma_scaling <= 0.0 {
+        return i64::MIN;
+    }
+    let step = 2.0 * std::f64::consts::E * max_error as f64;
+    (f64::from(luma_scaling).ln() / step).round() as i64
+}
+
+/// The luma scaling that a quantized value stands for, the inverse of `quantize_luma_scaling`.
+pub fn dequantize_luma_scaling(quantized: i64, max_error: f32) -> f32 {
+    if quantized == i64::MIN {
+        return 0.0;
+    }
+    let step = 2.0 * std::f64::consts::E * max_error as f64;
+    (quantized as f64 * step).exp() as f32
+}
+
+/// The luma scaling that `LutCache::get` builds the LUT for when it is asked for `luma_scaling`.
+pub fn cached_luma_scaling(luma_scaling: f32, max_error: f32) -> f32 {
+    dequantize_luma_scaling(quantize_luma_scaling(luma_scaling, max_error), max_error)
+}
+
+/// A bounded, thread-safe cache of mask LUTs.
+/// Consecutive frames often have (almost) the same average,
+/// so we can avoid rebuilding the LUT for every single frame.
+///
+/// `K` holds everything besides the luma scaling that the LUT depends on, e.g. the bit depth.
+pub struct LutCache<K, T> {
+    capacity: usize,
+    // Most recently used first
+    luts: Mutex<VecDeque<Entry<K, T>>>,
+}
+
+impl<K: Copy + Eq, T> LutCache<K, T> {
+    pub fn new(capacity: usize) -> Self {
+        LutCache {
+            capacity,
+            luts: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the LUT for the given key and luma scaling, calling `build` if it isn’t cached yet.
+    /// LUTs are reused for luma scalings that change the mask by at most `max_error`.
+    ///
+    /// `build` receives the quantized luma scaling rather than the original value,
+    /// so the result does not depend on whether the LUT was cached.
+    pub fn get<F>(&self, key: K, luma_scaling: f32, max_error: f32, build: F) -> Arc<Vec<T>>
+    where
+        F: FnOnce(f32) -> Vec<T>,
+    {
+        let key = (key, quantize_luma_scaling(luma_scaling, max_error));
+        {
+            let mut luts = self.luts.lock().unwrap();
+            if let Some(index) = luts.iter().position(|(k, _)| *k == key) {
+                let entry = luts.remove(index).unwrap();
+                let lut = entry.1.clone();
+                luts.push_front(entry);
+                return lut;
+            }
+        }
+        // Build the LUT without holding the lock so other threads aren’t blocked.
+        // If two threads miss on the same key, we build it twice, which is harmless.
+        let lut = Arc::new(build(dequantize_luma_scaling(key.1, max_error)));
+        let mut luts = self.luts.lock().unwrap();
+        if !luts.iter().any(|(k, _)| *k == key) {
+            luts.push_front((key, lut.clone()));
+            luts.truncate(self.capacity);
+        }
+        lut
+    }
+
+    pub fn len(&self) -> usize {
+        self.luts.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Copy + Eq, T> Default for LutCache<K, T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+/// Room for this many frames that are requested at the same time, besides the temporal radius.
+pub const FRAMES_IN_FLIGHT: usize = 32;
+
+/// A bounded, thread-safe cache of frame averages by frame number.
+/// With temporal smoothing, every frame is needed for the masks of all frames in its radius,
+/// and computing the average can take a full pass over the frame.
+pub struct AverageCache {
+    capacity: usize,
+    // Most recently inserted first
+    averages: Mutex<VecDeque<(usize, f32)>>,
+}
+
+impl AverageCache {
+    pub fn new(capacity: usize) -> Self {
+        AverageCache {
+            capacity,
+            averages: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Enough for all frames around the ones that are in flight with the given temporal radius.
+    pub fn for_radius(temporal_radius: usize) -> Self {
+        Self::new(2 * temporal_radius + 1 + FRAMES_IN_FLIGHT)
+    }
+
+    /// Returns the average of frame `n`, calling `compute` if it isn’t cached yet.
+    /// Errors are passed on and not cached.
+    pub fn get<E>(&self, n: usize, compute: impl FnOnce() -> Result<f32, E>) -> Result<f32, E> {
+        if let Some(&(_, average)) = self.averages.lock().unwrap().iter().find(|(i, _)| *i == n) {
+            return Ok(average);
+        }
+        // Like for the LUTs, the lock isn’t held while computing.
+        let average = compute()?;
+        let mut averages = self.averages.lock().unwrap();
+        if !averages.iter().any(|(i, _)| *i == n) {
+            averages.push_front((n, average));
+            averages.truncate(self.capacity);
+        }
+        Ok(average)
+    }
+
+    pub fn len(&self) -> usize {
+        self.averages.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lut_cache() {
+        let cache = LutCache::new(2);
+        let mut builds = 0;
+        let mut get = |ls| {
+            cache.get(8, ls, 0.001, |ls| {
+                builds += 1;
+                vec![(ls * 10.0).round()]
+            })
+        };
+        assert_eq!(*get(1.0), vec![10.0]);
+        // Close enough to reuse the same LUT
+        assert_eq!(*get(1.001), vec![10.0]);
+        assert_eq!(*get(2.0), vec![20.0]);
+        assert_eq!(*get(1.0), vec![10.0]);
+        // Evicts 2.0 because 1.0 was used more recently
+        assert_eq!(*get(3.0), vec![30.0]);
+        assert_eq!(*get(1.0), vec![10.0]);
+        assert_eq!(*get(2.0), vec![20.0]);
+        assert_eq!(builds, 4);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_lut_cache_key() {
+        let cache = LutCache::default();
+        assert_eq!(*cache.get(8, 1.0, 0.001, |_| vec![8]), vec![8]);
+        assert_eq!(*cache.get(10, 1.0, 0.001, |_| vec![10]), vec![10]);
+        assert_eq!(*cache.get(8, 1.0, 0.001, |_| vec![0]), vec![8]);
+        assert_eq!(*cache.get(8, 0.0, 0.001, |ls| vec![ls as i32]), vec![0]);
+    }
+
+    #[test]
+    fn test_quantize_luma_scaling() {
+        for &max_error in &[0.25 / 255.0, 0.25 / 65535.0] {
+            for &ls in &[0.001, 0.5, 1.0, 7.3, 100.0] {
+                let quantized =
+                    dequantize_luma_scaling(quantize_luma_scaling(ls, max_error), max_error);
+                // Half a step in the log domain
+                assert!((quantized / ls).ln().abs() <= std::f32::consts::E * max_error * 1.001);
+            }
+            assert_eq!(
+                dequantize_luma_scaling(quantize_luma_scaling(0.0, max_error), max_error),
+                0.0
+            );
+        }
+    }
+    #[test]
+    fn test_average_cache() {
+        let cache = AverageCache::new(2);
+        let mut computed = 0;
+        let mut get = |n| {
+            cache.get(n, || -> Result<f32, ()> {
+                computed += 1;
+                Ok(n as f32 / 10.0)
+            })
+        };
+        assert_eq!(get(1), Ok(0.1));
+        assert_eq!(get(1), Ok(0.1));
+        assert_eq!(get(2), Ok(0.2));
+        // Evicts frame 1, the oldest one
+        assert_eq!(get(3), Ok(0.3));
+        assert_eq!(get(2), Ok(0.2));
+        assert_eq!(get(1), Ok(0.1));
+        assert_eq!(computed, 4);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(4, || Err("failed")), Err("failed"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(AverageCache::for_radius(2).capacity, 5 + FRAMES_IN_FLIGHT);
+    }
+}
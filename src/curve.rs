@@ -0,0 +1,281 @@
+use super::mask::{get_mask_base, get_mask_value};
+use super::simd::fast_pow;
+use super::PLUGIN_NAME;
+use failure::Error;
+
+/// Number of points at which a user-supplied curve is checked to stay within 0-1.
+const VALIDATION_STEPS: usize = 4096;
+/// How far a user-supplied curve may leave the 0-1 range (e.g. due to rounded coefficients).
+const VALIDATION_TOLERANCE: f32 = 0.0001;
+
+/// The base shape of the mask, which is then raised to the power of the effective luma scaling.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Curve {
+    /// The polynomial from the original adaptive_grain.
+    #[default]
+    AdaptiveGrain,
+    /// A user-supplied polynomial with the coefficients in ascending order of degree,
+    /// i.e. `[a, b, c]` is `a + b*x + c*x²`.
+    Polynomial(Vec<f32>),
+    /// `1 - x^gamma`
+    Gamma(f32),
+    /// A falling logistic function, scaled so that it goes from 1 at 0 to 0 at 1.
+    Sigmoid { midpoint: f32, steepness: f32 },
+    /// Linear interpolation between (x, y) control points, sorted by x.
+    /// Values outside of the first and last point are extended horizontally.
+    PiecewiseLinear(Vec<(f32, f32)>),
+}
+
+impl Curve {
+    /// Creates a polynomial curve and checks that it stays within 0-1 for inputs in 0-1.
+    pub fn polynomial(coefficients: Vec<f32>) -> Result<Self, Error> {
+        if coefficients.is_empty() {
+            bail!("{}: coefficients must not be empty", PLUGIN_NAME);
+        }
+        let curve = Curve::Polynomial(coefficients);
+        curve.validate()?;
+        Ok(curve)
+    }
+
+    /// Creates a curve from its name and the parameters for that curve type.
+    /// `coefficients` are only used (and required) for polynomial curves.
+    pub fn from_name(
+        name: &str,
+        params: &[f32],
+        coefficients: Option<Vec<f32>>,
+    ) -> Result<Self, Error> {
+        let curve = match (name, params) {
+            ("adaptivegrain", []) => return Ok(Curve::AdaptiveGrain),
+            ("polynomial", []) => {
+                return Curve::polynomial(coefficients.ok_or_else(|| {
+                    format_err!("{}: polynomial curves need coefficients", PLUGIN_NAME)
+                })?)
+            }
+            ("gamma", []) => Curve::Gamma(1.0),
+            ("gamma", &[gamma]) if gamma > 0.0 => Curve::Gamma(gamma),
+            ("sigmoid", []) => Curve::Sigmoid {
+                midpoint: 0.5,
+                steepness: 10.0,
+            },
+            ("sigmoid", &[midpoint]) => Curve::Sigmoid {
+                midpoint,
+                steepness: 10.0,
+            },
+            ("sigmoid", &[midpoint, steepness]) if steepness > 0.0 => Curve::Sigmoid {
+                midpoint,
+                steepness,
+            },
+            ("linear", points) if points.len() >= 4 && points.len() % 2 == 0 => {
+                let points: Vec<(f32, f32)> = points.chunks(2).map(|p| (p[0], p[1])).collect();
+                if points.windows(2).any(|w| w[0].0 >= w[1].0) {
+                    bail!(
+                        "{}: the control points must be sorted by x without duplicates",
+                        PLUGIN_NAME
+                    );
+                }
+                Curve::PiecewiseLinear(points)
+            }
+            ("adaptivegrain", _)
+            | ("polynomial", _)
+            | ("gamma", _)
+            | ("sigmoid", _)
+            | ("linear", _) => {
+                bail!("{}: invalid curve_params for {} curve", PLUGIN_NAME, name)
+            }
+            _ => bail!("{}: unknown curve {}", PLUGIN_NAME, name),
+        };
+        if coefficients.is_some() {
+            bail!(
+                "{}: coefficients are only used for polynomial curves",
+                PLUGIN_NAME
+            );
+        }
+        curve.validate()?;
+        Ok(curve)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for i in 0..=VALIDATION_STEPS {
+            let x = i as f32 / VALIDATION_STEPS as f32;
+            let y = self.base(x);
+            if !(-VALIDATION_TOLERANCE..=1.0 + VALIDATION_TOLERANCE).contains(&y) {
+                bail!(
+                    "{}: the curve must stay within [0, 1] on [0, 1], but it is {} at {}",
+                    PLUGIN_NAME,
+                    y,
+                    x
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The value of the curve before the luma scaling is applied.
+    #[inline]
+    pub fn base(&self, x: f32) -> f32 {
+        match self {
+            Curve::AdaptiveGrain => get_mask_value(x, 1.0),
+            Curve::Polynomial(coefficients) => coefficients
+                .iter()
+                .rev()
+                .fold(0.0, |acc, &c| acc.mul_add(x, c)),
+            Curve::Gamma(gamma) => 1.0 - x.clamp(0.0, 1.0).powf(*gamma),
+            Curve::Sigmoid {
+                midpoint,
+                steepness,
+            } => {
+                let logistic = |x: f32| 1.0 / (1.0 + (steepness * (x - midpoint)).exp());
+                (logistic(x) - logistic(1.0)) / (logistic(0.0) - logistic(1.0))
+            }
+            Curve::PiecewiseLinear(points) => {
+                let i = points.iter().position(|&(px, _)| px > x);
+                match i {
+                    Some(0) => points[0].1,
+                    Some(i) => {
+                        let (x0, y0) = points[i - 1];
+                        let (x1, y1) = points[i];
+                        y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+                    }
+                    None => points[points.len() - 1].1,
+                }
+            }
+        }
+    }
+
+    #[inline]
+    pub fn mask_value(&self, x: f32, luma_scaling: f32) -> f32 {
+        match self {
+            // Avoid the generic evaluation for the default case.
+            Curve::AdaptiveGrain => get_mask_value(x, luma_scaling),
+            // Clamp to avoid NaN from slightly negative values within the validation tolerance.
+            _ => f32::powf(self.base(x).clamp(0.0, 1.0), luma_scaling),
+        }
+    }
+
+    /// Like `mask_value`, but with `simd::fast_pow` instead of `powf`.
+    #[inline]
+    pub fn mask_value_fast(&self, x: f32, luma_scaling: f32) -> f32 {
+        let base = match self {
+            Curve::AdaptiveGrain => get_mask_base(x),
+            _ => self.base(x),
+        };
+        fast_pow(base.clamp(0.0, 1.0), luma_scaling)
+    }
+
+    #[inline]
+    pub fn mask_value_clamping(&self, x: f32, luma_scaling: f32) -> f32 {
+        self.mask_value(x.clamp(0.0, 1.0), luma_scaling)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mask::{calc_luma_scaling, FLOAT_RANGE};
+
+    #[test]
+    fn test_polynomial() {
+        // The original polynomial, expanded
+        let curve = Curve::polynomial(vec![1.0, -1.124, 9.466, -36.624, 45.47, -18.188]).unwrap();
+        let luma_scaling = calc_luma_scaling(0.5, 10.0);
+        FLOAT_RANGE.iter().for_each(|&x| {
+            let expected = Curve::AdaptiveGrain.mask_value(x, luma_scaling);
+            let value = curve.mask_value(x, luma_scaling);
+            assert!(
+                (value - expected).abs() < 0.0001,
+                "Mask was wrong at position {}, expected {}, got {}",
+                x,
+                expected,
+                value
+            );
+        });
+        // Linear
+        let curve = Curve::polynomial(vec![1.0, -1.0]).unwrap();
+        assert_eq!(curve.mask_value(0.25, 1.0), 0.75);
+        assert_eq!(curve.mask_value(0.5, 2.0), 0.25);
+    }
+
+    #[test]
+    fn test_curve_families() {
+        let gamma = Curve::from_name("gamma", &[2.0], None).unwrap();
+        assert_eq!(gamma.base(0.0), 1.0);
+        assert_eq!(gamma.base(0.5), 0.75);
+        assert_eq!(gamma.base(1.0), 0.0);
+        assert_eq!(gamma.mask_value(0.5, 2.0), 0.5625);
+
+        let sigmoid = Curve::from_name("sigmoid", &[0.3, 20.0], None).unwrap();
+        assert!((sigmoid.base(0.0) - 1.0).abs() < 0.00001);
+        assert!(sigmoid.base(1.0).abs() < 0.00001);
+        assert!(sigmoid.base(0.2) > 0.8);
+        assert!(sigmoid.base(0.4) < 0.2);
+        let default_sigmoid = Curve::from_name("sigmoid", &[], None).unwrap();
+        assert!((default_sigmoid.base(0.5) - 0.5).abs() < 0.00001);
+
+        let linear = Curve::from_name("linear", &[0.1, 1.0, 0.5, 0.8, 0.9, 0.0], None).unwrap();
+        assert_eq!(linear.base(0.0), 1.0);
+        assert_eq!(linear.base(0.1), 1.0);
+        assert!((linear.base(0.3) - 0.9).abs() < 0.00001);
+        assert!((linear.base(0.7) - 0.4).abs() < 0.00001);
+        assert_eq!(linear.base(1.0), 0.0);
+
+        assert_eq!(
+            Curve::from_name("adaptivegrain", &[], None).unwrap(),
+            Curve::AdaptiveGrain
+        );
+        assert!(Curve::from_name("polynomial", &[], Some(vec![1.0, -1.0])).is_ok());
+    }
+
+    #[test]
+    fn test_mask_value_fast() {
+        use crate::simd::FAST_POW_MAX_ERROR;
+
+        let curves = [
+            Curve::AdaptiveGrain,
+            Curve::from_name("gamma", &[0.5], None).unwrap(),
+            Curve::from_name("sigmoid", &[0.3, 20.0], None).unwrap(),
+            Curve::from_name("linear", &[0.1, 1.0, 0.5, 0.8, 0.9, 0.0], None).unwrap(),
+        ];
+        for curve in &curves {
+            for &luma_scaling in &[0.0, 0.1, 1.0, 4.0, 40.0] {
+                for &x in &FLOAT_RANGE {
+                    let expected = curve.mask_value(x, luma_scaling);
+                    let value = curve.mask_value_fast(x, luma_scaling);
+                    assert!(
+                        (value - expected).abs() <= FAST_POW_MAX_ERROR,
+                        "{:?} was wrong at {} with luma scaling {}, expected {}, got {}",
+                        curve,
+                        x,
+                        luma_scaling,
+                        expected,
+                        value
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_curve_family_validation() {
+        assert!(Curve::from_name("cubic", &[], None).is_err());
+        assert!(Curve::from_name("polynomial", &[], None).is_err());
+        assert!(Curve::from_name("gamma", &[], Some(vec![1.0])).is_err());
+        assert!(Curve::from_name("gamma", &[-1.0], None).is_err());
+        assert!(Curve::from_name("gamma", &[1.0, 2.0], None).is_err());
+        assert!(Curve::from_name("sigmoid", &[0.5, 0.0], None).is_err());
+        assert!(Curve::from_name("linear", &[0.0, 1.0], None).is_err());
+        assert!(Curve::from_name("linear", &[0.0, 1.0, 1.0], None).is_err());
+        assert!(Curve::from_name("linear", &[0.5, 1.0, 0.2, 0.0], None).is_err());
+        assert!(Curve::from_name("linear", &[0.0, 1.0, 1.0, 1.5], None).is_err());
+    }
+
+    #[test]
+    fn test_polynomial_validation() {
+        assert!(Curve::polynomial(vec![]).is_err());
+        assert!(Curve::polynomial(vec![1.0, 1.0]).is_err());
+        assert!(Curve::polynomial(vec![0.0, -1.0]).is_err());
+        // (1 - 2x)² only touches 0 in the middle
+        assert!(Curve::polynomial(vec![1.0, -4.0, 4.0]).is_ok());
+        // Dips below 0 in the middle
+        assert!(Curve::polynomial(vec![1.0, -4.5, 4.0]).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use super::mask::{
+    calc_luma_scaling, get_mask_value_clamping, lut_input, MaskSample, MAX_LUT_DEPTH,
+};
+use super::stats::PlaneStats;
+use super::{check_format, PLUGIN_NAME};
+use failure::Error;
+use vapoursynth::core::CoreRef;
+use vapoursynth::plugins::{Filter, FrameContext};
+use vapoursynth::prelude::*;
+use vapoursynth::video_info::VideoInfo;
+
+pub struct Grain<'core> {
+    pub source: Node<'core>,
+    pub luma_scaling: f32,
+    /// Variance of the grain in 8-bit units, like grain.Add’s `var`.
+    pub var: f32,
+    pub seed: u64,
+}
+
+/// A small xorshift64* generator.
+/// We don’t need anything fancy here, but the grain has to be reproducible for a given seed and frame.
+pub struct Rng {
+    state: u64,
+    spare: Option<f32>,
+}
+
+impl Rng {
+    pub fn new(seed: u64, n: usize) -> Self {
+        // splitmix64 to spread similar seeds and frame numbers over the entire state space.
+        let mut z = seed ^ (n as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        Rng {
+            // xorshift gets stuck on 0
+            state: if z == 0 { 0x9E37_79B9_7F4A_7C15 } else { z },
+            spare: None,
+        }
+    }
+
+    #[inline]
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniformly distributed in (0, 1).
+    #[inline]
+    fn next_f32(&mut self) -> f32 {
+        ((self.next_u64() >> 40) as f32 + 0.5) / (1u32 << 24) as f32
+    }
+
+    /// Standard normal distribution using the Box-Muller transform.
+    #[inline]
+    pub fn next_gaussian(&mut self) -> f32 {
+        if let Some(spare) = self.spare.take() {
+            return spare;
+        }
+        let radius = (-2.0 * self.next_f32().ln()).sqrt();
+        let angle = 2.0 * std::f32::consts::PI * self.next_f32();
+        self.spare = Some(radius * angle.sin());
+        radius * angle.cos()
+    }
+}
+
+/// Standard deviation of the grain for a pixel with the normalized value `x`, normalized as well.
+/// `sigma` is in 8-bit units like `var`, and is reached where the mask is 1.
+#[inline]
+pub fn grain_strength(x: f32, luma_scaling: f32, sigma: f32) -> f32 {
+    get_mask_value_clamping(x, luma_scaling) * sigma / 255.0
+}
+
+/// The standard deviation of the grain in sample units for every value of integer input with this depth.
+pub fn int_strength_lut(depth: u8, luma_scaling: f32, sigma: f32) -> Vec<f32> {
+    let max = ((1u64 << depth) - 1) as f32;
+    lut_input(depth)
+        .map(|x| grain_strength(x, luma_scaling, sigma) * max)
+        .collect()
+}
+
+macro_rules! int_grain {
+    ($type:ty, $fname:ident) => {
+        fn $fname(
+            frame: &mut FrameRefMut,
+            src_frame: &FrameRef,
+            depth: u8,
+            luma_scaling: f32,
+            sigma: f32,
+            rng: &mut Rng,
+        ) {
+            let max = ((1u64 << depth) - 1) as f32;
+            let shift = depth.saturating_sub(MAX_LUT_DEPTH);
+            let lut = int_strength_lut(depth, luma_scaling, sigma);
+            // Values above the peak of the bit depth get the strength of the peak, like in the mask
+            let last = lut.len() - 1;
+            for row in 0..frame.height(0) {
+                for (pixel, src_pixel) in frame
+                    .plane_row_mut::<$type>(0, row)
+                    .iter_mut()
+                    .zip(src_frame.plane_row::<$type>(0, row))
+                {
+                    let strength = lut[((src_pixel >> shift) as usize).min(last)];
+                    let value = *src_pixel as f32 + rng.next_gaussian() * strength;
+                    *pixel = value.round().clamp(0.0, max) as $type;
+                }
+            }
+        }
+    };
+}
+
+/// Adds grain to single or half precision input, which is converted to f32 for the calculation.
+fn grain_float<T: MaskSample>(
+    frame: &mut FrameRefMut,
+    src_frame: &FrameRef,
+    luma_scaling: f32,
+    sigma: f32,
+    rng: &mut Rng,
+) {
+    for row in 0..frame.height(0) {
+        frame
+            .plane_row_mut::<T>(0, row)
+            .iter_mut()
+            .zip(src_frame.plane_row::<T>(0, row))
+            .for_each(|(pixel, src_pixel)| {
+                let src_pixel = src_pixel.to_mask(1.0);
+                let strength = grain_strength(src_pixel, luma_scaling, sigma);
+                *pixel = T::from_mask(src_pixel + rng.next_gaussian() * strength, 1.0);
+            });
+    }
+}
+
+impl<'core> Filter<'core> for Grain<'core> {
+    fn video_info(&self, _api: API, _core: CoreRef<'core>) -> Vec<VideoInfo<'core>> {
+        vec![self.source.info()]
+    }
+
+    fn get_frame_initial(
+        &self,
+        _api: API,
+        _core: CoreRef<'core>,
+        context: FrameContext,
+        n: usize,
+    ) -> Result<Option<FrameRef<'core>>, Error> {
+        self.source.request_frame_filter(context, n);
+        Ok(None)
+    }
+
+    fn get_frame(
+        &self,
+        _api: API,
+        core: CoreRef<'core>,
+        context: FrameContext,
+        n: usize,
+    ) -> Result<FrameRef<'core>, Error> {
+        let src_frame = self.source.get_frame_filter(context, n).ok_or_else(|| {
+            format_err!("Could not retrieve source frame. This shouldn’t happen.")
+        })?;
+        // Chroma and props are taken from the source, only luma is written.
+        let mut frame = FrameRefMut::copy_of(core, &src_frame);
+        let format = src_frame.format();
+        check_format(format)?;
+        let luma_scaling =
+            calc_luma_scaling(PlaneStats::get(&src_frame)?.average, self.luma_scaling);
+        let sigma = self.var.sqrt();
+        let mut rng = Rng::new(self.seed, n);
+
+        match format.sample_type() {
+            SampleType::Integer => {
+                let depth = format.bits_per_sample();
+                match depth {
+                    0..=8 => {
+                        int_grain!(u8, grain_8bit);
+                        grain_8bit(&mut frame, &src_frame, depth, luma_scaling, sigma, &mut rng)
+                    }
+                    9..=16 => {
+                        int_grain!(u16, grain_16bit);
+                        grain_16bit(&mut frame, &src_frame, depth, luma_scaling, sigma, &mut rng)
+                    }
+                    17..=32 => {
+                        int_grain!(u32, grain_32bit);
+                        grain_32bit(&mut frame, &src_frame, depth, luma_scaling, sigma, &mut rng)
+                    }
+                    _ => bail!(format!(
+                        "{}: input depth {} not supported",
+                        PLUGIN_NAME, depth
+                    )),
+                }
+            }
+            SampleType::Float => match format.bits_per_sample() {
+                16 => {
+                    grain_float::<half::f16>(&mut frame, &src_frame, luma_scaling, sigma, &mut rng)
+                }
+                _ => grain_float::<f32>(&mut frame, &src_frame, luma_scaling, sigma, &mut rng),
+            },
+        }
+        Ok(frame.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_distribution() {
+        let mut rng = Rng::new(0, 0);
+        let samples: Vec<f32> = (0..100_000).map(|_| rng.next_gaussian()).collect();
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        let var = samples.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / samples.len() as f32;
+        assert!(mean.abs() < 0.02, "mean was {}", mean);
+        assert!((var - 1.0).abs() < 0.02, "variance was {}", var);
+    }
+
+    #[test]
+    fn test_rng_reproducible() {
+        let mut a = Rng::new(42, 7);
+        let mut b = Rng::new(42, 7);
+        let mut c = Rng::new(42, 8);
+        let a: Vec<f32> = (0..16).map(|_| a.next_gaussian()).collect();
+        let b: Vec<f32> = (0..16).map(|_| b.next_gaussian()).collect();
+        let c: Vec<f32> = (0..16).map(|_| c.next_gaussian()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_grain_strength() {
+        let sigma = 4f32.sqrt();
+        // Black areas of a dark frame get the full strength of `var`
+        let dark = calc_luma_scaling(0.1, 10.0);
+        assert!((grain_strength(0.0, dark, sigma) - sigma / 255.0).abs() < 0.000001);
+        assert_eq!(
+            grain_strength(-0.5, dark, sigma),
+            grain_strength(0.0, dark, sigma)
+        );
+        // White areas get none
+        for &average in &[0.3, 0.5, 0.8] {
+            let luma_scaling = calc_luma_scaling(average, 10.0);
+            assert!(grain_strength(1.0, luma_scaling, sigma) < 0.000001);
+            assert_eq!(
+                grain_strength(1.5, luma_scaling, sigma),
+                grain_strength(1.0, luma_scaling, sigma)
+            );
+        }
+        // Brighter frames have less grain in the midtones
+        let bright = calc_luma_scaling(0.8, 10.0);
+        assert!(grain_strength(0.5, bright, sigma) < grain_strength(0.5, dark, sigma));
+        assert_eq!(grain_strength(0.3, dark, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_int_strength_lut() {
+        // Integer input scales `var` like float input, relative to its peak value
+        let (luma_scaling, sigma) = (calc_luma_scaling(0.4, 10.0), 1.5);
+        for &depth in &[8u8, 10, 16] {
+            let max = ((1u64 << depth) - 1) as f32;
+            let lut = int_strength_lut(depth, luma_scaling, sigma);
+            assert_eq!(lut.len(), 1 << depth);
+            for (i, &strength) in lut.iter().enumerate().step_by(17) {
+                let float = grain_strength(i as f32 / max, luma_scaling, sigma);
+                assert!((strength / max - float).abs() < 0.000001);
+            }
+            assert!((lut[0] - sigma * max / 255.0).abs() < 0.001);
+            assert!(lut[lut.len() - 1] < 0.000001 * max);
+        }
+    }
+}
@@ -3,14 +3,27 @@ extern crate failure;
 #[macro_use]
 extern crate vapoursynth;
 
+pub mod cache;
+pub mod curve;
+pub mod grain;
+pub mod luma;
 pub mod mask;
+pub mod simd;
+pub mod stats;
 
-use self::mask::Mask;
+use self::cache::{AverageCache, LutCache};
+use self::curve::Curve;
+use self::grain::Grain;
+use self::luma::{ColorRange, Transfer};
+use self::mask::{mask_format, Mask, MaskRange};
+use self::simd::SimdLevel;
+use self::stats::Crop;
 use failure::Error;
+use rayon::ThreadPoolBuilder;
 use vapoursynth::api::API;
 use vapoursynth::core::CoreRef;
-use vapoursynth::format::SampleType;
-use vapoursynth::map::Map;
+use vapoursynth::format::{Format, FormatID, SampleType};
+use vapoursynth::map::{Map, ValueIter};
 use vapoursynth::node::Node;
 use vapoursynth::plugins::{Filter, FilterArgument, Metadata};
 use vapoursynth::video_info::Property;
@@ -18,26 +31,200 @@ use vapoursynth::video_info::Property;
 pub const PLUGIN_NAME: &str = "adaptivegrain";
 pub const PLUGIN_IDENTIFIER: &str = "moe.kageru.adaptivegrain";
 
+/// Checks if the plugin can process frames of the given format.
+pub fn check_format(format: Format) -> Result<(), Error> {
+    if format.sample_type() == SampleType::Float
+        && format.bits_per_sample() != 16
+        && format.bits_per_sample() != 32
+    {
+        bail!(
+            "{}: only half and single precision float input is supported",
+            PLUGIN_NAME
+        );
+    }
+    Ok(())
+}
+
+fn check_clip(clip: &Node) -> Result<(), Error> {
+    match clip.info().format {
+        Property::Constant(format) => check_format(format),
+        // Variable format clips are checked for every frame instead.
+        Property::Variable => Ok(()),
+    }
+}
+
 make_filter_function! {
     MaskFunction, "Mask"
+    #[allow(clippy::too_many_arguments)]
     fn create_mask<'core>(
         _api: API,
-        _core: CoreRef<'core>,
+        core: CoreRef<'core>,
         clip: Node<'core>,
-        luma_scaling: Option<f64>
+        luma_scaling: Option<f64>,
+        temporal_radius: Option<i64>,
+        temporal_decay: Option<f64>,
+        scene_aware: Option<i64>,
+        coefficients: Option<ValueIter<'_, 'core, f64>>,
+        curve: Option<&[u8]>,
+        curve_params: Option<ValueIter<'_, 'core, f64>>,
+        range: Option<i64>,
+        transfer: Option<i64>,
+        output: Option<i64>,
+        min: Option<f64>,
+        max: Option<f64>,
+        invert: Option<i64>,
+        detail_radius: Option<i64>,
+        detail_strength: Option<f64>,
+        blur_radius: Option<i64>,
+        propagate_props: Option<i64>,
+        left: Option<i64>,
+        right: Option<i64>,
+        top: Option<i64>,
+        bottom: Option<i64>,
+        auto_crop: Option<i64>,
+        border_threshold: Option<f64>,
+        fast: Option<i64>,
+        interpolate: Option<i64>,
+        threads: Option<i64>
     ) -> Result<Option<Box<dyn Filter<'core> + 'core>>, Error> {
         let luma_scaling = luma_scaling.unwrap_or(10.0) as f32;
-        if let Property::Constant(format) = clip.info().format {
-            if !(format.sample_type() == SampleType::Float && format.bits_per_sample() != 32) {
-                return Ok(Some(Box::new(Mask {
-                    source: clip,
-                    luma_scaling
-                })));
-            } else {
-                bail!("Half precision float input is not supported");
+        let temporal_radius = temporal_radius.unwrap_or(0);
+        if temporal_radius < 0 {
+            bail!("temporal_radius must not be negative");
+        }
+        let temporal_decay = temporal_decay.unwrap_or(1.0) as f32;
+        if !(temporal_decay > 0.0 && temporal_decay <= 1.0) {
+            bail!("temporal_decay must be in (0, 1]");
+        }
+        let coefficients: Option<Vec<f32>> = coefficients.map(|c| c.map(|c| c as f32).collect());
+        let curve_params: Vec<f32> = curve_params.map_or_else(Vec::new, |p| p.map(|p| p as f32).collect());
+        let curve = match (curve, coefficients) {
+            (Some(name), coefficients) => Curve::from_name(
+                std::str::from_utf8(name)?,
+                &curve_params,
+                coefficients,
+            )?,
+            (None, _) if !curve_params.is_empty() => bail!("curve_params require a curve"),
+            (None, Some(coefficients)) => Curve::polynomial(coefficients)?,
+            (None, None) => Curve::default(),
+        };
+        let range = match range {
+            Some(range) => Some(
+                ColorRange::from_int(range)
+                    .ok_or_else(|| format_err!("range must be 0 (full) or 1 (limited)"))?,
+            ),
+            None => None,
+        };
+        let output = match output {
+            Some(id) => {
+                let format = core
+                    .get_format(FormatID::from(id as i32))
+                    .ok_or_else(|| format_err!("output must be a valid format ID"))?;
+                check_format(format)?;
+                Some(mask_format(core, format).ok_or_else(|| format_err!("could not register output format"))?)
             }
+            None => None,
+        };
+        let mask_range = MaskRange {
+            min: min.unwrap_or(0.0) as f32,
+            max: max.unwrap_or(1.0) as f32,
+            invert: invert.unwrap_or(0) != 0,
+        };
+        if !(0.0..=1.0).contains(&mask_range.min) || !(0.0..=1.0).contains(&mask_range.max) {
+            bail!("min and max must be in [0, 1]");
+        }
+        let detail_radius = detail_radius.unwrap_or(0);
+        if detail_radius < 0 {
+            bail!("detail_radius must not be negative");
+        }
+        let detail_strength = detail_strength.unwrap_or(0.5) as f32;
+        if !(0.0..=1.0).contains(&detail_strength) {
+            bail!("detail_strength must be in [0, 1]");
+        }
+        let blur_radius = blur_radius.unwrap_or(0);
+        if blur_radius < 0 {
+            bail!("blur_radius must not be negative");
+        }
+        let crop = [left, right, top, bottom];
+        if crop.iter().any(|&c| c.unwrap_or(0) < 0) {
+            bail!("left, right, top, and bottom must not be negative");
+        }
+        let [left, right, top, bottom] = crop.map(|c| c.unwrap_or(0) as usize);
+        let crop = Crop { left, right, top, bottom };
+        // Clips with variable resolution can only be checked per frame
+        if let Property::Constant(resolution) = clip.info().resolution {
+            crop.region(resolution.width, resolution.height)?;
+        }
+        let border_threshold = border_threshold.unwrap_or(0.08) as f32;
+        if !(0.0..=1.0).contains(&border_threshold) {
+            bail!("border_threshold must be in [0, 1]");
+        }
+        let pool = match threads.unwrap_or(1) {
+            threads if threads < 0 => bail!("threads must not be negative"),
+            1 => None,
+            // 0 lets rayon pick one thread per CPU
+            threads => Some(
+                ThreadPoolBuilder::new()
+                    .num_threads(threads as usize)
+                    .thread_name(|i| format!("{}-{}", PLUGIN_NAME, i))
+                    .build()?,
+            ),
+        };
+        check_clip(&clip)?;
+        Ok(Some(Box::new(Mask {
+            source: clip,
+            output,
+            luma_scaling,
+            curve,
+            mask_range,
+            temporal_radius: temporal_radius as usize,
+            temporal_decay,
+            scene_aware: scene_aware.unwrap_or(0) != 0,
+            range,
+            transfer: transfer.map(Transfer::from_int),
+            detail_radius: detail_radius as usize,
+            detail_strength,
+            blur_radius: blur_radius as usize,
+            crop,
+            auto_crop: auto_crop.unwrap_or(0) != 0,
+            border_threshold,
+            propagate_props: propagate_props.unwrap_or(1) != 0,
+            simd: SimdLevel::detect(),
+            fast: fast.unwrap_or(0) != 0,
+            interpolate: interpolate.unwrap_or(0) != 0,
+            pool,
+            average_cache: AverageCache::for_radius(temporal_radius as usize),
+            lut_cache_8bit: LutCache::default(),
+            lut_cache_16bit: LutCache::default(),
+            lut_cache_32bit: LutCache::default(),
+            lut_cache_half: LutCache::default(),
+            lut_cache_float: LutCache::default(),
+        })))
+    }
+}
+
+make_filter_function! {
+    GrainFunction, "Grain"
+    fn create_grain<'core>(
+        _api: API,
+        _core: CoreRef<'core>,
+        clip: Node<'core>,
+        luma_scaling: Option<f64>,
+        var: Option<f64>,
+        seed: Option<i64>
+    ) -> Result<Option<Box<dyn Filter<'core> + 'core>>, Error> {
+        let luma_scaling = luma_scaling.unwrap_or(10.0) as f32;
+        let var = var.unwrap_or(1.0) as f32;
+        if var < 0.0 {
+            bail!("var must not be negative");
         }
-        bail!("Variable format input is not supported")
+        check_clip(&clip)?;
+        Ok(Some(Box::new(Grain {
+            source: clip,
+            luma_scaling,
+            var,
+            seed: seed.unwrap_or(0) as u64,
+        })))
     }
 }
 
@@ -48,5 +235,8 @@ export_vapoursynth_plugin! {
         name: "Adaptive grain",
         read_only: false,
     },
-    [ MaskFunction::new() ]
+    [
+        MaskFunction::new(),
+        GrainFunction::new()
+    ]
 }
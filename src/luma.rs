@@ -0,0 +1,154 @@
+//! Conversions of the source luma into the full-range SDR values that the mask curves are made for.
+use vapoursynth::prelude::*;
+
+/// The luma range as signaled by the `_ColorRange` frame prop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorRange {
+    Full,
+    Limited,
+}
+
+impl ColorRange {
+    /// Uses the same values as `_ColorRange`, i.e. 0 for full and 1 for limited range.
+    pub fn from_int(value: i64) -> Option<Self> {
+        match value {
+            0 => Some(ColorRange::Full),
+            1 => Some(ColorRange::Limited),
+            _ => None,
+        }
+    }
+
+    pub fn from_props(props: &Map) -> Option<Self> {
+        props
+            .get::<i64>("_ColorRange")
+            .ok()
+            .and_then(ColorRange::from_int)
+    }
+
+    /// Maps a normalized (0-1) luma value of a clip with the given bit depth to full range.
+    /// Float input should pass a depth of 8, which gives the usual 16/255 to 235/255 range.
+    /// The result is not clamped, so values outside of the nominal range end up outside of 0-1.
+    #[inline]
+    pub fn to_full(self, x: f32, depth: u8) -> f32 {
+        match self {
+            ColorRange::Full => x,
+            ColorRange::Limited => {
+                let scale = (1u64 << depth.saturating_sub(8)) as f32;
+                let max = ((1u64 << depth) - 1) as f32;
+                (x * max - 16.0 * scale) / (219.0 * scale)
+            }
+        }
+    }
+}
+
+/// Luminance of SDR reference white in HDR content according to BT.2408.
+const SDR_WHITE_NITS: f32 = 203.0;
+/// Peak luminance assumed for the HLG display OOTF.
+const HLG_PEAK_NITS: f32 = 1000.0;
+/// HLG system gamma for a 1000 nits display.
+const HLG_SYSTEM_GAMMA: f32 = 1.2;
+/// BT.1886 gamma for the SDR encoding.
+const SDR_GAMMA: f32 = 2.4;
+
+/// The transfer characteristics as signaled by the `_Transfer` frame prop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// Everything that isn’t HDR. The curves were made for this, so it is left as is.
+    Sdr,
+    /// SMPTE ST 2084
+    Pq,
+    /// ARIB STD-B67
+    Hlg,
+}
+
+impl Transfer {
+    /// Uses the same values as `_Transfer`, i.e. 16 for PQ and 18 for HLG.
+    pub fn from_int(value: i64) -> Self {
+        match value {
+            16 => Transfer::Pq,
+            18 => Transfer::Hlg,
+            _ => Transfer::Sdr,
+        }
+    }
+
+    pub fn from_props(props: &Map) -> Self {
+        props
+            .get::<i64>("_Transfer")
+            .map_or(Transfer::Sdr, Transfer::from_int)
+    }
+
+    /// Maps a full range code value to the SDR code value with the same (approximate) brightness.
+    /// SDR input is returned unchanged, HDR input is clamped to 0-1.
+    #[inline]
+    pub fn to_sdr(self, x: f32) -> f32 {
+        let nits = match self {
+            Transfer::Sdr => return x,
+            Transfer::Pq => pq_eotf(x.clamp(0.0, 1.0)),
+            Transfer::Hlg => {
+                HLG_PEAK_NITS * hlg_inverse_oetf(x.clamp(0.0, 1.0)).powf(HLG_SYSTEM_GAMMA)
+            }
+        };
+        // Highlights above reference white are clipped, the mask is 0 there anyway.
+        (nits / SDR_WHITE_NITS).min(1.0).powf(1.0 / SDR_GAMMA)
+    }
+}
+
+/// PQ code value to absolute luminance in nits.
+fn pq_eotf(x: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+    let e = x.powf(1.0 / M2);
+    10000.0 * ((e - C1).max(0.0) / (C2 - C3 * e)).powf(1.0 / M1)
+}
+
+/// HLG code value to normalized scene light.
+fn hlg_inverse_oetf(x: f32) -> f32 {
+    const A: f32 = 0.178_832_77;
+    const B: f32 = 1.0 - 4.0 * A;
+    const C: f32 = 0.559_910_7;
+    if x <= 0.5 {
+        x * x / 3.0
+    } else {
+        (((x - C) / A).exp() + B) / 12.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limited_to_full() {
+        let limited = ColorRange::Limited;
+        assert_eq!(limited.to_full(16.0 / 255.0, 8), 0.0);
+        assert!((limited.to_full(235.0 / 255.0, 8) - 1.0).abs() < 0.000001);
+        assert!(limited.to_full(0.0, 8) < 0.0);
+        assert!(limited.to_full(1.0, 8) > 1.0);
+        assert_eq!(limited.to_full(64.0 / 1023.0, 10), 0.0);
+        assert!((limited.to_full(940.0 / 1023.0, 10) - 1.0).abs() < 0.000001);
+        assert!((limited.to_full(502.0 / 1023.0, 10) - 0.5).abs() < 0.000001);
+        assert_eq!(ColorRange::Full.to_full(0.3, 10), 0.3);
+    }
+
+    #[test]
+    fn test_transfer_to_sdr() {
+        assert_eq!(Transfer::Sdr.to_sdr(0.3), 0.3);
+        assert_eq!(Transfer::Sdr.to_sdr(1.2), 1.2);
+        for &transfer in &[Transfer::Pq, Transfer::Hlg] {
+            assert_eq!(transfer.to_sdr(0.0), 0.0);
+            assert_eq!(transfer.to_sdr(1.0), 1.0);
+            assert_eq!(transfer.to_sdr(-0.1), 0.0);
+        }
+        assert!((pq_eotf(1.0) - 10000.0).abs() < 1.0);
+        assert!((pq_eotf(0.5) - 92.25).abs() < 0.1);
+        // Reference white (203 nits) is around 58% in PQ and 75% in HLG
+        assert!((Transfer::Pq.to_sdr(0.58) - 1.0).abs() < 0.01);
+        assert!((Transfer::Hlg.to_sdr(0.75) - 1.0).abs() < 0.01);
+        // 18% grey, 2.4 gamma
+        assert!((Transfer::Pq.to_sdr(0.4109) - 0.4894).abs() < 0.005);
+        assert!((Transfer::Hlg.to_sdr(0.4362) - 0.4894).abs() < 0.005);
+    }
+}
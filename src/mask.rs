@@ -1,113 +1,873 @@
-use super::PLUGIN_NAME;
+use super::cache::{cached_luma_scaling, AverageCache, LutCache};
+use super::curve::Curve;
+use super::luma::{ColorRange, Transfer};
+use super::simd::{self, LutIndex, SimdLevel};
+use super::stats::{
+    frame_borders, mapped_average, scene_bounds, temporal_average, Crop, PlaneStats,
+};
+use super::{check_format, PLUGIN_NAME};
 use failure::Error;
-use std::ptr;
+use half::f16;
+use rayon::ThreadPool;
+use std::ops::RangeInclusive;
+use std::{ptr, slice};
 use vapoursynth::core::CoreRef;
-use vapoursynth::format::ColorFamily;
+use vapoursynth::format::{ColorFamily, Format};
 use vapoursynth::plugins::{Filter, FrameContext};
 use vapoursynth::prelude::*;
 use vapoursynth::video_info::{Property, VideoInfo};
 
 pub struct Mask<'core> {
     pub source: Node<'core>,
+    /// The (gray) output format if it should differ from the source format.
+    pub output: Option<Format<'core>>,
     pub luma_scaling: f32,
+    pub curve: Curve,
+    pub mask_range: MaskRange,
+    /// Number of frames before and after the current one that are used to smooth the average.
+    pub temporal_radius: usize,
+    /// Weight factor per frame of distance to the current frame. 1.0 weighs all frames equally.
+    pub temporal_decay: f32,
+    /// Don’t smooth the average across scene changes.
+    pub scene_aware: bool,
+    /// Overrides the `_ColorRange` frame prop if set.
+    pub range: Option<ColorRange>,
+    /// Overrides the `_Transfer` frame prop if set.
+    pub transfer: Option<Transfer>,
+    /// Radius of the window used to measure texture. 0 disables the detail attenuation.
+    pub detail_radius: usize,
+    /// How much the mask is reduced in fully textured areas (0-1).
+    pub detail_strength: f32,
+    /// Borders that are excluded from the frame average.
+    pub crop: Crop,
+    /// Also exclude black borders that are detected in every frame.
+    pub auto_crop: bool,
+    /// Brightest (normalized) value that still counts as black for `auto_crop`.
+    pub border_threshold: f32,
+    /// Copy the props of the source frame to the mask.
+    pub propagate_props: bool,
+    /// Radius of the box blur applied to the finished mask. 0 disables the blur.
+    pub blur_radius: usize,
+    /// The kernels that are used, usually the best that the CPU supports.
+    pub simd: SimdLevel,
+    /// Use `simd::fast_pow` instead of `powf` for float input.
+    pub fast: bool,
+    /// Interpolate float input in an `InterpolatedLut` instead of evaluating the curve for every pixel.
+    pub interpolate: bool,
+    /// Splits the rows of each frame into stripes that are processed in parallel. `None` is serial.
+    pub pool: Option<ThreadPool>,
+    /// Frame averages (after cropping) that can be reused for the neighbouring masks.
+    pub average_cache: AverageCache,
+    pub lut_cache_8bit: LutCache<LutKey, u8>,
+    pub lut_cache_16bit: LutCache<LutKey, u16>,
+    pub lut_cache_32bit: LutCache<LutKey, u32>,
+    pub lut_cache_half: LutCache<LutKey, f16>,
+    pub lut_cache_float: LutCache<LutKey, f32>,
+}
+
+/// Linear remapping of the mask values into a target range, optionally inverted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaskRange {
+    pub min: f32,
+    pub max: f32,
+    pub invert: bool,
+}
+
+impl Default for MaskRange {
+    fn default() -> Self {
+        MaskRange {
+            min: 0.0,
+            max: 1.0,
+            invert: false,
+        }
+    }
+}
+
+impl MaskRange {
+    #[inline]
+    pub fn apply(self, value: f32) -> f32 {
+        let value = if self.invert { 1.0 - value } else { value };
+        value.mul_add(self.max - self.min, self.min)
+    }
+
+    /// The inverse of `apply`. If `min` and `max` are equal, every value comes from 0.
+    #[inline]
+    pub fn unapply(self, value: f32) -> f32 {
+        if self.max == self.min {
+            return 0.0;
+        }
+        let value = (value - self.min) / (self.max - self.min);
+        if self.invert {
+            1.0 - value
+        } else {
+            value
+        }
+    }
+
+    /// Reduces a remapped mask value in textured areas, where `deviation` is the local deviation of the source.
+    /// The mask is multiplied with 1 - `strength` in fully textured areas before it is remapped,
+    /// so the result stays within [min, max] and inverted masks get larger instead.
+    #[inline]
+    pub fn attenuate(self, value: f32, deviation: f32, strength: f32) -> f32 {
+        let factor = 1.0 - strength * (deviation / DETAIL_SATURATION).min(1.0);
+        self.apply(self.unapply(value) * factor)
+    }
+}
+
+/// Everything besides the luma scaling that an integer LUT depends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LutKey {
+    pub depth: u8,
+    pub output_depth: u8,
+    pub range: ColorRange,
+    pub transfer: Transfer,
 }
 
 #[rustfmt::skip]
-static FLOAT_RANGE: [f32; 256] = [0.0, 0.003921569, 0.007843138, 0.011764706, 0.015686275, 0.019607844, 0.023529412, 0.02745098, 0.03137255, 0.03529412, 0.039215688, 0.043137256, 0.047058824, 0.050980393, 0.05490196, 0.05882353, 0.0627451, 0.06666667, 0.07058824, 0.07450981, 0.078431375, 0.08235294, 0.08627451, 0.09019608, 0.09411765, 0.09803922, 0.101960786, 0.105882354, 0.10980392, 0.11372549, 0.11764706, 0.12156863, 0.1254902, 0.12941177, 0.13333334, 0.13725491, 0.14117648, 0.14509805, 0.14901961, 0.15294118, 0.15686275, 0.16078432, 0.16470589, 0.16862746, 0.17254902, 0.1764706, 0.18039216, 0.18431373, 0.1882353, 0.19215687, 0.19607843, 0.2, 0.20392157, 0.20784314, 0.21176471, 0.21568628, 0.21960784, 0.22352941, 0.22745098, 0.23137255, 0.23529412, 0.23921569, 0.24313726, 0.24705882, 0.2509804, 0.25490198, 0.25882354, 0.2627451, 0.26666668, 0.27058825, 0.27450982, 0.2784314, 0.28235295, 0.28627452, 0.2901961, 0.29411766, 0.29803923, 0.3019608, 0.30588236, 0.30980393, 0.3137255, 0.31764707, 0.32156864, 0.3254902, 0.32941177, 0.33333334, 0.3372549, 0.34117648, 0.34509805, 0.34901962, 0.3529412, 0.35686275, 0.36078432, 0.3647059, 0.36862746, 0.37254903, 0.3764706, 0.38039216, 0.38431373, 0.3882353, 0.39215687, 0.39607844, 0.4, 0.40392157, 0.40784314, 0.4117647, 0.41568628, 0.41960785, 0.42352942, 0.42745098, 0.43137255, 0.43529412, 0.4392157, 0.44313726, 0.44705883, 0.4509804, 0.45490196, 0.45882353, 0.4627451, 0.46666667, 0.47058824, 0.4745098, 0.47843137, 0.48235294, 0.4862745, 0.49019608, 0.49411765, 0.49803922, 0.5019608, 0.5058824, 0.50980395, 0.5137255, 0.5176471, 0.52156866, 0.5254902, 0.5294118, 0.53333336, 0.5372549, 0.5411765, 0.54509807, 0.54901963, 0.5529412, 0.5568628, 0.56078434, 0.5647059, 0.5686275, 0.57254905, 0.5764706, 0.5803922, 0.58431375, 0.5882353, 0.5921569, 0.59607846, 0.6, 0.6039216, 0.60784316, 0.6117647, 0.6156863, 0.61960787, 0.62352943, 0.627451, 0.6313726, 0.63529414, 0.6392157, 0.6431373, 0.64705884, 0.6509804, 0.654902, 0.65882355, 0.6627451, 0.6666667, 0.67058825, 0.6745098, 0.6784314, 0.68235296, 0.6862745, 0.6901961, 0.69411767, 0.69803923, 0.7019608, 0.7058824, 0.70980394, 0.7137255, 0.7176471, 0.72156864, 0.7254902, 0.7294118, 0.73333335, 0.7372549, 0.7411765, 0.74509805, 0.7490196, 0.7529412, 0.75686276, 0.7607843, 0.7647059, 0.76862746, 0.77254903, 0.7764706, 0.78039217, 0.78431374, 0.7882353, 0.7921569, 0.79607844, 0.8, 0.8039216, 0.80784315, 0.8117647, 0.8156863, 0.81960785, 0.8235294, 0.827451, 0.83137256, 0.8352941, 0.8392157, 0.84313726, 0.84705883, 0.8509804, 0.85490197, 0.85882354, 0.8627451, 0.8666667, 0.87058824, 0.8745098, 0.8784314, 0.88235295, 0.8862745, 0.8901961, 0.89411765, 0.8980392, 0.9019608, 0.90588236, 0.9098039, 0.9137255, 0.91764706, 0.92156863, 0.9254902, 0.92941177, 0.93333334, 0.9372549, 0.9411765, 0.94509804, 0.9490196, 0.9529412, 0.95686275, 0.9607843, 0.9647059, 0.96862745, 0.972549, 0.9764706, 0.98039216, 0.9843137, 0.9882353, 0.99215686, 0.99607843, 1.0];
+pub static FLOAT_RANGE: [f32; 256] = [0.0, 0.003921569, 0.007843138, 0.011764706, 0.015686275, 0.019607844, 0.023529412, 0.02745098, 0.03137255, 0.03529412, 0.039215688, 0.043137256, 0.047058824, 0.050980393, 0.05490196, 0.05882353, 0.0627451, 0.06666667, 0.07058824, 0.07450981, 0.078431375, 0.08235294, 0.08627451, 0.09019608, 0.09411765, 0.09803922, 0.101960786, 0.105882354, 0.10980392, 0.11372549, 0.11764706, 0.12156863, 0.1254902, 0.12941177, 0.13333334, 0.13725491, 0.14117648, 0.14509805, 0.14901961, 0.15294118, 0.15686275, 0.16078432, 0.16470589, 0.16862746, 0.17254902, 0.1764706, 0.18039216, 0.18431373, 0.1882353, 0.19215687, 0.19607843, 0.2, 0.20392157, 0.20784314, 0.21176471, 0.21568628, 0.21960784, 0.22352941, 0.22745098, 0.23137255, 0.23529412, 0.23921569, 0.24313726, 0.24705882, 0.2509804, 0.25490198, 0.25882354, 0.2627451, 0.26666668, 0.27058825, 0.27450982, 0.2784314, 0.28235295, 0.28627452, 0.2901961, 0.29411766, 0.29803923, 0.3019608, 0.30588236, 0.30980393, 0.3137255, 0.31764707, 0.32156864, 0.3254902, 0.32941177, 0.33333334, 0.3372549, 0.34117648, 0.34509805, 0.34901962, 0.3529412, 0.35686275, 0.36078432, 0.3647059, 0.36862746, 0.37254903, 0.3764706, 0.38039216, 0.38431373, 0.3882353, 0.39215687, 0.39607844, 0.4, 0.40392157, 0.40784314, 0.4117647, 0.41568628, 0.41960785, 0.42352942, 0.42745098, 0.43137255, 0.43529412, 0.4392157, 0.44313726, 0.44705883, 0.4509804, 0.45490196, 0.45882353, 0.4627451, 0.46666667, 0.47058824, 0.4745098, 0.47843137, 0.48235294, 0.4862745, 0.49019608, 0.49411765, 0.49803922, 0.5019608, 0.5058824, 0.50980395, 0.5137255, 0.5176471, 0.52156866, 0.5254902, 0.5294118, 0.53333336, 0.5372549, 0.5411765, 0.54509807, 0.54901963, 0.5529412, 0.5568628, 0.56078434, 0.5647059, 0.5686275, 0.57254905, 0.5764706, 0.5803922, 0.58431375, 0.5882353, 0.5921569, 0.59607846, 0.6, 0.6039216, 0.60784316, 0.6117647, 0.6156863, 0.61960787, 0.62352943, 0.627451, 0.6313726, 0.63529414, 0.6392157, 0.6431373, 0.64705884, 0.6509804, 0.654902, 0.65882355, 0.6627451, 0.6666667, 0.67058825, 0.6745098, 0.6784314, 0.68235296, 0.6862745, 0.6901961, 0.69411767, 0.69803923, 0.7019608, 0.7058824, 0.70980394, 0.7137255, 0.7176471, 0.72156864, 0.7254902, 0.7294118, 0.73333335, 0.7372549, 0.7411765, 0.74509805, 0.7490196, 0.7529412, 0.75686276, 0.7607843, 0.7647059, 0.76862746, 0.77254903, 0.7764706, 0.78039217, 0.78431374, 0.7882353, 0.7921569, 0.79607844, 0.8, 0.8039216, 0.80784315, 0.8117647, 0.8156863, 0.81960785, 0.8235294, 0.827451, 0.83137256, 0.8352941, 0.8392157, 0.84313726, 0.84705883, 0.8509804, 0.85490197, 0.85882354, 0.8627451, 0.8666667, 0.87058824, 0.8745098, 0.8784314, 0.88235295, 0.8862745, 0.8901961, 0.89411765, 0.8980392, 0.9019608, 0.90588236, 0.9098039, 0.9137255, 0.91764706, 0.92156863, 0.9254902, 0.92941177, 0.93333334, 0.9372549, 0.9411765, 0.94509804, 0.9490196, 0.9529412, 0.95686275, 0.9607843, 0.9647059, 0.96862745, 0.972549, 0.9764706, 0.98039216, 0.9843137, 0.9882353, 0.99215686, 0.99607843, 1.0];
 
 #[inline]
 pub fn get_mask_value(x: f32, luma_scaling: f32) -> f32 {
-    f32::powf(
-        1.0 - (x
-            * (x.mul_add(
-                x.mul_add(x.mul_add(x.mul_add(18.188, -45.47), 36.624), -9.466),
-                1.124,
-            ))),
-        luma_scaling,
-    )
+    f32::powf(get_mask_base(x), luma_scaling)
+}
+
+/// The mask before the luma scaling is applied.
+#[inline]
+pub fn get_mask_base(x: f32) -> f32 {
+    1.0 - (x
+        * (x.mul_add(
+            x.mul_add(x.mul_add(x.mul_add(18.188, -45.47), 36.624), -9.466),
+            1.124,
+        )))
 }
 
 #[inline]
 pub fn get_mask_value_clamping(x: f32, luma_scaling: f32) -> f32 {
-    get_mask_value(x.min(1.0).max(0.0), luma_scaling)
+    get_mask_value(x.clamp(0.0, 1.0), luma_scaling)
 }
 
-macro_rules! from_property {
-    ($prop: expr) => {
-        match $prop {
-            Property::Constant(p) => p,
-            Property::Variable => unreachable!(),
+/// Frame prop that overrides the `luma_scaling` argument for a single frame.
+pub const LUMA_SCALING_PROP: &str = "AdgLumaScaling";
+
+/// Local standard deviation (of normalized luma) at which an area counts as fully textured.
+pub const DETAIL_SATURATION: f32 = 0.1;
+
+/// Integer input up to this depth gets one LUT entry per possible value.
+/// Deeper input is shifted down to this depth before the lookup.
+pub const MAX_LUT_DEPTH: u8 = 16;
+
+/// Cached LUTs may differ from the exact mask by this much (a quarter of an 8-bit step),
+/// regardless of the output format, so deeper masks reuse LUTs as often as 8-bit masks.
+pub const LUT_CACHE_MAX_ERROR: f32 = 0.25 / 255.0;
+
+/// Builds the mask LUT for integer input. `max` is the peak value of the output format.
+pub fn build_lut<U: MaskSample>(
+    curve: &Curve,
+    mask_range: MaskRange,
+    key: LutKey,
+    luma_scaling: f32,
+    max: f32,
+) -> Vec<U> {
+    let lut_depth = key.depth.min(MAX_LUT_DEPTH);
+    lut_input(key.depth)
+        .map(|x| {
+            let x = key.transfer.to_sdr(key.range.to_full(x, lut_depth));
+            let value = curve.mask_value_clamping(x, luma_scaling);
+            U::from_mask(mask_range.apply(value), max)
+        })
+        .collect()
+}
+
+/// The normalized (0-1) input values for the LUT of an integer clip with the given bit depth.
+pub fn lut_input(depth: u8) -> impl Iterator<Item = f32> {
+    let lut_depth = depth.min(MAX_LUT_DEPTH);
+    let max = ((1u32 << lut_depth) - 1) as f32;
+    (0..1u32 << lut_depth).map(move |i| i as f32 / max)
+}
+
+/// Number of cells of the interpolated LUT for float input.
+pub const INTERPOLATION_STEPS: usize = 4096;
+/// Maximum error of the interpolated LUT.
+pub const INTERPOLATION_MAX_ERROR: f32 = 1.0 / 65535.0;
+
+/// A table of mask values for float input that is linearly interpolated.
+/// Where the curve is too steep for that (e.g. close to white with a luma scaling below 1),
+/// the mask function is evaluated directly instead. Input outside of 0-1 is clamped.
+pub struct InterpolatedLut {
+    values: Vec<f32>,
+    // One per cell, i.e. between two values
+    exact: Vec<bool>,
+}
+
+impl InterpolatedLut {
+    pub fn new(mask_fn: impl Fn(f32) -> f32) -> Self {
+        let steps = INTERPOLATION_STEPS as f32;
+        let values: Vec<f32> = (0..=INTERPOLATION_STEPS)
+            .map(|i| mask_fn(i as f32 / steps))
+            .collect();
+        // The curves are smooth enough that checking a few points in each cell
+        // with some headroom finds the cells where interpolating is too inaccurate.
+        let inaccurate: Vec<bool> = values
+            .windows(2)
+            .enumerate()
+            .map(|(i, w)| {
+                [0.25, 0.5, 0.75].iter().any(|&t| {
+                    let expected = mask_fn((i as f32 + t) / steps);
+                    let error = (expected - (w[1] - w[0]).mul_add(t, w[0])).abs();
+                    error.is_nan() || error > INTERPOLATION_MAX_ERROR / 2.0
+                })
+            })
+            .collect();
+        // Their neighbors are evaluated directly as well in case the error grows faster than that.
+        let exact = (0..INTERPOLATION_STEPS)
+            .map(|i| {
+                inaccurate[i.saturating_sub(1)..(i + 2).min(INTERPOLATION_STEPS)].contains(&true)
+            })
+            .collect();
+        InterpolatedLut { values, exact }
+    }
+
+    /// `mask_fn` has to be the same function that the LUT was built with.
+    #[inline]
+    pub fn get(&self, x: f32, mask_fn: impl Fn(f32) -> f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        let position = x * INTERPOLATION_STEPS as f32;
+        let i = (position as usize).min(INTERPOLATION_STEPS - 1);
+        if self.exact[i] {
+            return mask_fn(x);
+        }
+        let (a, b) = (self.values[i], self.values[i + 1]);
+        (b - a).mul_add(position - i as f32, a)
+    }
+
+    /// Number of cells that are evaluated directly.
+    pub fn exact_cells(&self) -> usize {
+        self.exact.iter().filter(|&&exact| exact).count()
+    }
+}
+
+/// A sample type that the mask can be written as.
+pub trait MaskSample: Component + Copy + Send + Sync {
+    /// Converts a mask value (0-1) to a sample. `max` is the peak value of integer formats.
+    fn from_mask(value: f32, max: f32) -> Self;
+
+    /// Like `from_mask`, but rounds to the nearest integer value,
+    /// so that values read with `to_mask` are written back unchanged.
+    #[inline]
+    fn from_mask_rounded(value: f32, max: f32) -> Self {
+        Self::from_mask(value, max)
+    }
+
+    /// Converts a sample to a normalized value, the inverse of `from_mask`.
+    fn to_mask(self, max: f32) -> f32;
+
+    /// Looks up `src >> shift` in the LUT. All types but half precision use a vectorized gather.
+    /// Indices past the end of the LUT are clamped to the last entry.
+    #[inline]
+    fn lookup_row<T: LutIndex>(
+        _simd: SimdLevel,
+        src: &[T],
+        lut: &[Self],
+        shift: u8,
+        dst: &mut [Self],
+    ) {
+        let last = lut.len() - 1;
+        for (pixel, &src_pixel) in dst.iter_mut().zip(src) {
+            let i = ((src_pixel.into() >> shift) as usize).min(last);
+            unsafe {
+                ptr::write(pixel, lut[i]);
+            }
+        }
+    }
+}
+
+macro_rules! int_mask_sample {
+    ($type:ty) => {
+        impl MaskSample for $type {
+            #[inline]
+            fn from_mask(value: f32, max: f32) -> Self {
+                (value * max) as $type
+            }
+
+            #[inline]
+            fn from_mask_rounded(value: f32, max: f32) -> Self {
+                (value * max).round().clamp(0.0, max) as $type
+            }
+
+            #[inline]
+            fn to_mask(self, max: f32) -> f32 {
+                self as f32 / max
+            }
+
+            #[inline]
+            fn lookup_row<T: LutIndex>(
+                simd: SimdLevel,
+                src: &[T],
+                lut: &[$type],
+                shift: u8,
+                dst: &mut [$type],
+            ) {
+                simd::gather(simd, src, lut, shift, dst);
+            }
         }
     };
 }
 
-macro_rules! int_filter {
-    ($type:ty, $fname:ident) => {
-        fn $fname(frame: &mut FrameRefMut, src_frame: FrameRef, depth: u8, luma_scaling: f32) {
-            let max = ((1 << depth) - 1) as f32;
-            let lut: Vec<$type> = FLOAT_RANGE
-                .iter()
-                .map(|x| (get_mask_value(*x, luma_scaling) * max) as $type)
-                .collect();
-            for row in 0..frame.height(0) {
-                for (pixel, src_pixel) in frame
-                    .plane_row_mut::<$type>(0, row)
-                    .iter_mut()
-                    .zip(src_frame.plane_row::<$type>(0, row))
-                {
-                    let i = (src_pixel >> (depth - 8)) as usize;
-                    unsafe {
-                        ptr::write(pixel, lut[i].clone());
-                    }
+int_mask_sample!(u8);
+int_mask_sample!(u16);
+int_mask_sample!(u32);
+
+impl MaskSample for f16 {
+    #[inline]
+    fn from_mask(value: f32, _max: f32) -> Self {
+        f16::from_f32(value)
+    }
+
+    #[inline]
+    fn to_mask(self, _max: f32) -> f32 {
+        self.to_f32()
+    }
+}
+
+impl MaskSample for f32 {
+    #[inline]
+    fn from_mask(value: f32, _max: f32) -> Self {
+        value
+    }
+
+    #[inline]
+    fn to_mask(self, _max: f32) -> f32 {
+        self
+    }
+
+    #[inline]
+    fn lookup_row<T: LutIndex>(
+        simd: SimdLevel,
+        src: &[T],
+        lut: &[f32],
+        shift: u8,
+        dst: &mut [f32],
+    ) {
+        simd::gather_f32(simd, src, lut, shift, dst);
+    }
+}
+
+/// The peak value of an integer format, 1.0 for float formats.
+fn peak_value(format: Format) -> f32 {
+    match format.sample_type() {
+        SampleType::Integer => ((1u64 << format.bits_per_sample()) - 1) as f32,
+        SampleType::Float => 1.0,
+    }
+}
+
+/// Calls `f` with the index of the first row and the rows of each stripe,
+/// and returns the sum of what the calls return (e.g. the sum of the values they wrote).
+/// Without a thread pool, all rows are a single stripe.
+/// Every row is written by exactly one call, so the result doesn’t depend on the number of threads.
+pub fn process_stripes<U, F>(rows: &mut [&mut [U]], pool: Option<&ThreadPool>, f: F) -> f64
+where
+    U: Send,
+    F: Fn(usize, &mut [&mut [U]]) -> f64 + Sync,
+{
+    let pool = match pool {
+        Some(pool) if rows.len() > 1 => pool,
+        _ => return f(0, rows),
+    };
+    let stripe_height = rows.len().div_ceil(pool.current_num_threads());
+    let mut sums = vec![0.0; rows.len().div_ceil(stripe_height)];
+    let f = &f;
+    pool.scope(|scope| {
+        for ((i, stripe), sum) in rows.chunks_mut(stripe_height).enumerate().zip(&mut sums) {
+            scope.spawn(move |_| *sum = f(i * stripe_height, stripe));
+        }
+    });
+    sums.iter().sum()
+}
+
+/// The sum of a row of the mask in sample values, which is accumulated for `AdgMaskAverage`
+/// while the row is still in the cache.
+fn row_sum<U: MaskSample>(row: &[U]) -> f64 {
+    row.iter().map(|pixel| pixel.to_mask(1.0) as f64).sum()
+}
+
+/// All rows of the first plane, which can be borrowed separately unlike `plane_row_mut`.
+fn plane_rows_mut<'a, U: Component>(frame: &'a mut FrameRefMut) -> Vec<&'a mut [U]> {
+    (0..frame.height(0))
+        .map(|row| {
+            let row = frame.plane_row_mut::<U>(0, row);
+            // The rows don’t overlap, so they can be borrowed at the same time.
+            unsafe { slice::from_raw_parts_mut(row.as_mut_ptr(), row.len()) }
+        })
+        .collect()
+}
+
+/// Looks up the mask value of every pixel of integer input in the LUT.
+/// Like the other filters, this returns the sum of the mask (see `row_sum`).
+fn filter_int<T, U>(
+    frame: &mut FrameRefMut,
+    src_frame: &FrameRef,
+    lut: &[U],
+    shift: u8,
+    simd: SimdLevel,
+    pool: Option<&ThreadPool>,
+) -> f64
+where
+    T: Component + LutIndex,
+    U: MaskSample,
+{
+    process_stripes(&mut plane_rows_mut::<U>(frame), pool, |first, rows| {
+        let mut sum = 0.0;
+        for (i, row) in rows.iter_mut().enumerate() {
+            U::lookup_row(
+                simd,
+                src_frame.plane_row::<T>(0, first + i),
+                lut,
+                shift,
+                row,
+            );
+            sum += row_sum(row);
+        }
+        sum
+    })
+}
+
+/// `mask_fn` maps a source pixel to the mask value.
+/// Half precision input is converted to f32 for the calculation.
+fn filter_float<T, U>(
+    frame: &mut FrameRefMut,
+    src_frame: &FrameRef,
+    max: f32,
+    mask_fn: impl Fn(f32) -> f32 + Sync,
+    pool: Option<&ThreadPool>,
+) -> f64
+where
+    T: Component + Copy + Into<f32>,
+    U: MaskSample,
+{
+    process_stripes(&mut plane_rows_mut::<U>(frame), pool, |first, rows| {
+        let mut sum = 0.0;
+        for (i, row) in rows.iter_mut().enumerate() {
+            row.iter_mut()
+                .zip(src_frame.plane_row::<T>(0, first + i))
+                .for_each(|(pixel, src_pixel)| unsafe {
+                    ptr::write(pixel, U::from_mask(mask_fn((*src_pixel).into()), max));
+                });
+            sum += row_sum(row);
+        }
+        sum
+    })
+}
+
+/// Everything the vectorized float path needs. It only supports the default curve
+/// without HDR or limited range input, so `mask_fn` is reduced to clamping and the luma scaling.
+#[derive(Debug, Clone, Copy)]
+struct SimdFloat {
+    simd: SimdLevel,
+    fast: bool,
+    clamping: bool,
+    luma_scaling: f32,
+    mask_range: MaskRange,
+}
+
+/// Like `filter_float` for single precision input, but with the polynomial evaluated by the vectorized kernel.
+fn filter_float_simd<U: MaskSample>(
+    frame: &mut FrameRefMut,
+    src_frame: &FrameRef,
+    max: f32,
+    params: SimdFloat,
+    pool: Option<&ThreadPool>,
+) -> f64 {
+    let width = frame.width(0);
+    process_stripes(&mut plane_rows_mut::<U>(frame), pool, |first, rows| {
+        let mut values = vec![0.0; width];
+        let mut sum = 0.0;
+        for (i, row) in rows.iter_mut().enumerate() {
+            let src_row = src_frame.plane_row::<f32>(0, first + i);
+            if params.fast {
+                simd::mask_value_fast(
+                    params.simd,
+                    src_row,
+                    &mut values,
+                    params.clamping,
+                    params.luma_scaling,
+                );
+            } else {
+                simd::mask_base(params.simd, src_row, &mut values, params.clamping);
+                for value in values.iter_mut() {
+                    *value = value.powf(params.luma_scaling);
                 }
             }
+            row.iter_mut()
+                .zip(&values)
+                .for_each(|(pixel, &value)| unsafe {
+                    ptr::write(pixel, U::from_mask(params.mask_range.apply(value), max));
+                });
+            sum += row_sum(row);
         }
-    };
+        sum
+    })
 }
 
-fn filter_for_float(frame: &mut FrameRefMut, src_frame: FrameRef, luma_scaling: f32) {
-    for row in 0..frame.height(0) {
-        frame
-            .plane_row_mut::<f32>(0, row)
-            .iter_mut()
-            .zip(src_frame.plane_row::<f32>(0, row))
-            .for_each(|(pixel, src_pixel)| unsafe {
-                ptr::write(pixel, get_mask_value(*src_pixel, luma_scaling));
-            });
+fn filter_float_to<U: MaskSample>(
+    frame: &mut FrameRefMut,
+    src_frame: &FrameRef,
+    max: f32,
+    mask_fn: impl Fn(f32) -> f32 + Sync,
+    simd: Option<SimdFloat>,
+    pool: Option<&ThreadPool>,
+) -> f64 {
+    match (src_frame.format().bytes_per_sample(), simd) {
+        (2, _) => filter_float::<f16, U>(frame, src_frame, max, mask_fn, pool),
+        (_, Some(params)) => filter_float_simd::<U>(frame, src_frame, max, params, pool),
+        (_, None) => filter_float::<f32, U>(frame, src_frame, max, mask_fn, pool),
     }
 }
 
-fn filter_for_float_clamping(frame: &mut FrameRefMut, src_frame: FrameRef, luma_scaling: f32) {
-    for row in 0..frame.height(0) {
-        frame
-            .plane_row_mut::<f32>(0, row)
-            .iter_mut()
-            .zip(src_frame.plane_row::<f32>(0, row))
-            .for_each(|(pixel, src_pixel)| unsafe {
-                ptr::write(pixel, get_mask_value_clamping(*src_pixel, luma_scaling));
+/// Reads a row of the first plane into normalized values.
+fn read_row<T: MaskSample>(frame: &Frame, row: usize, values: &mut [f32]) {
+    let max = peak_value(frame.format());
+    for (value, pixel) in values.iter_mut().zip(frame.plane_row::<T>(0, row)) {
+        *value = pixel.to_mask(max);
+    }
+}
+
+/// A row of the first plane of a frame in any supported format as normalized values.
+pub fn plane_row_values(frame: &Frame, row: usize, values: &mut [f32]) {
+    let format = frame.format();
+    match (format.sample_type(), format.bytes_per_sample()) {
+        (SampleType::Integer, 1) => read_row::<u8>(frame, row, values),
+        (SampleType::Integer, 2) => read_row::<u16>(frame, row, values),
+        (SampleType::Integer, _) => read_row::<u32>(frame, row, values),
+        (SampleType::Float, 2) => read_row::<f16>(frame, row, values),
+        (SampleType::Float, _) => read_row::<f32>(frame, row, values),
+    }
+}
+
+/// A window of (2 * radius + 1) rows that slides down a plane for box filters.
+/// It keeps the column sums of the values (and optionally their squares) in the window,
+/// and copies of the rows that are still needed, so the scratch space only depends on the width.
+/// The window is cropped at the edges of the plane.
+struct SlidingWindow {
+    width: usize,
+    height: usize,
+    radius: usize,
+    // Ring buffer of the last 2 * radius + 2 rows
+    rows: Vec<f32>,
+    sums: Vec<f64>,
+    squares: Option<Vec<f64>>,
+}
+
+impl SlidingWindow {
+    fn new(width: usize, height: usize, radius: usize, squares: bool) -> Self {
+        SlidingWindow {
+            width,
+            height,
+            radius,
+            rows: vec![0.0; (2 * radius + 2) * width],
+            sums: vec![0.0; width],
+            squares: if squares {
+                Some(vec![0.0; width])
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Adds row `i`, whose values are written by `read_row`, and removes the row that leaves the window.
+    /// Returns the row that the window is centered on now, until every row has been the center once.
+    fn step(&mut self, i: usize, read_row: impl FnOnce(&mut [f32])) -> Option<usize> {
+        let slots = 2 * self.radius + 2;
+        if i < self.height {
+            let start = i % slots * self.width;
+            read_row(&mut self.rows[start..start + self.width]);
+            self.add(i, 1.0);
+        }
+        if i > 2 * self.radius {
+            self.add(i - 2 * self.radius - 1, -1.0);
+        }
+        i.checked_sub(self.radius).filter(|&y| y < self.height)
+    }
+
+    fn add(&mut self, row: usize, sign: f64) {
+        let start = row % (2 * self.radius + 2) * self.width;
+        let row = &self.rows[start..start + self.width];
+        for (sum, &value) in self.sums.iter_mut().zip(row) {
+            *sum += sign * value as f64;
+        }
+        if let Some(squares) = &mut self.squares {
+            for (sum, &value) in squares.iter_mut().zip(row) {
+                *sum += sign * (value as f64).powi(2);
+            }
+        }
+    }
+
+    /// The means of the values (or their squares) in the window around every pixel of the center row `y`.
+    fn means(&self, y: usize, squares: bool, means: &mut [f64]) {
+        let sums = match (&self.squares, squares) {
+            (Some(squares), true) => squares,
+            _ => &self.sums,
+        };
+        let (width, radius) = (self.width, self.radius);
+        let rows = (y + radius + 1).min(self.height) - y.saturating_sub(radius);
+        let mut sum: f64 = sums[..radius.min(width)].iter().sum();
+        for (x, mean) in means.iter_mut().enumerate() {
+            if x + radius < width {
+                sum += sums[x + radius];
+            }
+            if x > radius {
+                sum -= sums[x - radius - 1];
+            }
+            let columns = (x + radius + 1).min(width) - x.saturating_sub(radius);
+            *mean = sum / (rows * columns) as f64;
+        }
+    }
+}
+
+/// Local standard deviation in a (2 * radius + 1)² window around every pixel.
+/// The window is cropped at the edges of the plane.
+/// `read_row` writes the (normalized) values of a row, and `f` gets the deviation of every row in order.
+pub fn local_deviation(
+    width: usize,
+    height: usize,
+    radius: usize,
+    mut read_row: impl FnMut(usize, &mut [f32]),
+    mut f: impl FnMut(usize, &[f32]),
+) {
+    let mut window = SlidingWindow::new(width, height, radius, true);
+    let (mut means, mut squares) = (vec![0.0; width], vec![0.0; width]);
+    let mut deviation = vec![0.0; width];
+    for i in 0..height + radius {
+        if let Some(y) = window.step(i, |row| read_row(i, row)) {
+            window.means(y, false, &mut means);
+            window.means(y, true, &mut squares);
+            for ((d, &mean), &square) in deviation.iter_mut().zip(&means).zip(&squares) {
+                *d = (square - mean * mean).max(0.0).sqrt() as f32;
+            }
+            f(y, &deviation);
+        }
+    }
+}
+
+/// Multiplies the mask with 1 - `strength` where the source is fully textured, and less in flatter areas.
+fn attenuate_rows<U: MaskSample>(
+    frame: &mut FrameRefMut,
+    src_frame: &FrameRef,
+    radius: usize,
+    strength: f32,
+    mask_range: MaskRange,
+) -> f64 {
+    let max = peak_value(frame.format());
+    let (width, height) = (frame.width(0), frame.height(0));
+    let mut rows = plane_rows_mut::<U>(frame);
+    let mut sum = 0.0;
+    local_deviation(
+        width,
+        height,
+        radius,
+        |y, values| plane_row_values(src_frame, y, values),
+        |y, deviation| {
+            for (pixel, &deviation) in rows[y].iter_mut().zip(deviation) {
+                let value = mask_range.attenuate(pixel.to_mask(max), deviation, strength);
+                *pixel = U::from_mask_rounded(value, max);
+            }
+            sum += row_sum(rows[y]);
+        },
+    );
+    sum
+}
+
+/// Box blur with a (2 * radius + 1)² window, in place. The window is cropped at the edges of the plane.
+/// `max` is the peak value of integer samples. Returns the sum of the blurred samples.
+pub fn box_blur<T: MaskSample>(rows: &mut [&mut [T]], max: f32, radius: usize) -> f64 {
+    let (width, height) = (rows.first().map_or(0, |row| row.len()), rows.len());
+    let mut window = SlidingWindow::new(width, height, radius, false);
+    let mut means = vec![0.0; width];
+    let mut sum = 0.0;
+    for i in 0..height + radius {
+        // Rows are only overwritten after they have entered the window, which keeps a copy of them.
+        let center = window.step(i, |values| {
+            for (value, pixel) in values.iter_mut().zip(rows[i].iter()) {
+                *value = pixel.to_mask(max);
+            }
+        });
+        if let Some(y) = center {
+            window.means(y, false, &mut means);
+            for (pixel, &mean) in rows[y].iter_mut().zip(&means) {
+                *pixel = T::from_mask_rounded(mean as f32, max);
+            }
+            sum += row_sum(rows[y]);
+        }
+    }
+    sum
+}
+
+fn blur_rows<T: MaskSample>(frame: &mut FrameRefMut, radius: usize) -> f64 {
+    let max = peak_value(frame.format());
+    box_blur(&mut plane_rows_mut::<T>(frame), max, radius)
+}
+
+/// The gray format of the mask for a source with the given format.
+pub fn mask_format<'core>(core: CoreRef<'core>, format: Format<'core>) -> Option<Format<'core>> {
+    core.register_format(
+        ColorFamily::Gray,
+        format.sample_type(),
+        format.bits_per_sample(),
+        0,
+        0,
+    )
+}
+
+impl<'core> Mask<'core> {
+    /// The frames whose averages are used for the mask of frame `n`.
+    fn temporal_range(&self, n: usize) -> RangeInclusive<usize> {
+        let last = match self.source.info().num_frames {
+            Property::Constant(num_frames) => num_frames - 1,
+            // We can’t look ahead if we don’t know where the clip ends.
+            Property::Variable => n,
+        };
+        n.saturating_sub(self.temporal_radius)..=(n + self.temporal_radius).min(last)
+    }
+
+    /// The temporal range of frame `n`, cut off at the scene changes around it if `scene_aware` is set.
+    fn smoothing_range(
+        &self,
+        context: FrameContext,
+        n: usize,
+    ) -> Result<RangeInclusive<usize>, Error> {
+        let range = self.temporal_range(n);
+        if !self.scene_aware {
+            return Ok(range);
+        }
+        let first = *range.start();
+        let mut scene_changes = Vec::with_capacity(2 * self.temporal_radius + 1);
+        for i in range {
+            let frame = self.get_source_frame(context, i)?;
+            let props = frame.props();
+            scene_changes.push((
+                props.get::<i64>("_SceneChangePrev").unwrap_or(0) != 0,
+                props.get::<i64>("_SceneChangeNext").unwrap_or(0) != 0,
+            ));
+        }
+        let (start, end) = scene_bounds(&scene_changes, n - first);
+        Ok(first + start..=first + end)
+    }
+
+    /// Writes the mask for integer input using a (cached) LUT of the output sample type.
+    fn filter_int_to<U: MaskSample>(
+        &self,
+        frame: &mut FrameRefMut,
+        src_frame: &FrameRef,
+        cache: &LutCache<LutKey, U>,
+        key: LutKey,
+        luma_scaling: f32,
+        max: f32,
+    ) -> f64 {
+        let lut = cache.get(key, luma_scaling, LUT_CACHE_MAX_ERROR, |luma_scaling| {
+            build_lut(&self.curve, self.mask_range, key, luma_scaling, max)
+        });
+        let shift = key.depth.saturating_sub(MAX_LUT_DEPTH);
+        match src_frame.format().bytes_per_sample() {
+            1 => filter_int::<u8, U>(frame, src_frame, &lut, shift, self.simd, self.pool.as_ref()),
+            2 => filter_int::<u16, U>(frame, src_frame, &lut, shift, self.simd, self.pool.as_ref()),
+            _ => filter_int::<u32, U>(frame, src_frame, &lut, shift, self.simd, self.pool.as_ref()),
+        }
+    }
+
+    /// Reduces the mask where the source luma is textured, since grain isn’t visible there anyway.
+    fn attenuate_detail(&self, frame: &mut FrameRefMut, src_frame: &FrameRef) -> f64 {
+        let (radius, strength, range) = (self.detail_radius, self.detail_strength, self.mask_range);
+        let (src, format) = (src_frame, frame.format());
+        match (format.sample_type(), format.bytes_per_sample()) {
+            (SampleType::Integer, 1) => attenuate_rows::<u8>(frame, src, radius, strength, range),
+            (SampleType::Integer, 2) => attenuate_rows::<u16>(frame, src, radius, strength, range),
+            (SampleType::Integer, _) => attenuate_rows::<u32>(frame, src, radius, strength, range),
+            (SampleType::Float, 2) => attenuate_rows::<f16>(frame, src, radius, strength, range),
+            (SampleType::Float, _) => attenuate_rows::<f32>(frame, src, radius, strength, range),
+        }
+    }
+
+    /// Smooths the mask so it doesn’t reproduce source noise and aliasing.
+    fn blur(&self, frame: &mut FrameRefMut) -> f64 {
+        let radius = self.blur_radius;
+        let format = frame.format();
+        match (format.sample_type(), format.bytes_per_sample()) {
+            (SampleType::Integer, 1) => blur_rows::<u8>(frame, radius),
+            (SampleType::Integer, 2) => blur_rows::<u16>(frame, radius),
+            (SampleType::Integer, _) => blur_rows::<u32>(frame, radius),
+            (SampleType::Float, 2) => blur_rows::<f16>(frame, radius),
+            (SampleType::Float, _) => blur_rows::<f32>(frame, radius),
+        }
+    }
+
+    /// The range and transfer of a source frame, from the arguments or the frame props,
+    /// and the bit depth that the range conversion is based on.
+    fn luma_conversion(&self, frame: &Frame) -> (ColorRange, Transfer, u8) {
+        let format = frame.format();
+        let props = frame.props();
+        // Float clips are full range in practice, regardless of what the props say.
+        let range = self
+            .range
+            .or_else(|| match format.sample_type() {
+                SampleType::Integer => ColorRange::from_props(&props),
+                SampleType::Float => None,
+            })
+            .unwrap_or(ColorRange::Full);
+        let transfer = self
+            .transfer
+            .unwrap_or_else(|| Transfer::from_props(&props));
+        // Float input uses the 8-bit limited range scaled to 0-1
+        let range_depth = match format.sample_type() {
+            SampleType::Integer => format.bits_per_sample(),
+            SampleType::Float => 8,
+        };
+        (range, transfer, range_depth)
+    }
+
+    /// The average that the luma scaling is based on, i.e. without the cropped borders,
+    /// converted to full range SDR.
+    fn frame_average(&self, frame: &Frame) -> Result<f32, Error> {
+        let crop = if self.auto_crop {
+            let borders = frame_borders(frame, self.border_threshold);
+            self.crop
+                .with_borders(borders, frame.width(0), frame.height(0))
+        } else {
+            self.crop
+        };
+        let (range, transfer, range_depth) = self.luma_conversion(frame);
+        // The HDR conversion isn’t linear, so every pixel has to be converted before averaging.
+        // `PlaneStatsAverage` can’t be used for that.
+        if transfer != Transfer::Sdr {
+            return mapped_average(frame, crop, |x| {
+                transfer.to_sdr(range.to_full(x, range_depth))
             });
+        }
+        let average = if crop.is_empty() {
+            PlaneStats::get(frame)?.average
+        } else {
+            PlaneStats::from_region(frame, crop)?.average
+        };
+        Ok(range.to_full(average, range_depth))
+    }
+
+    fn get_source_frame(&self, context: FrameContext, n: usize) -> Result<FrameRef<'core>, Error> {
+        self.source
+            .get_frame_filter(context, n)
+            .ok_or_else(|| format_err!("Could not retrieve source frame. This shouldn’t happen."))
     }
 }
 
 impl<'core> Filter<'core> for Mask<'core> {
-    fn video_info(&self, _api: API, _core: CoreRef<'core>) -> Vec<VideoInfo<'core>> {
+    fn video_info(&self, _api: API, core: CoreRef<'core>) -> Vec<VideoInfo<'core>> {
         let info = self.source.info();
-        let format = match info.format {
-            Property::Variable => unreachable!(),
-            Property::Constant(format) => format,
+        let format = match (self.output, info.format) {
+            (Some(output), _) => Property::Constant(output),
+            (None, Property::Variable) => Property::Variable,
+            (None, Property::Constant(format)) => {
+                Property::Constant(mask_format(core, format).unwrap())
+            }
         };
         vec![VideoInfo {
-            format: Property::Constant(
-                _core
-                    .register_format(
-                        ColorFamily::Gray,
-                        format.sample_type(),
-                        format.bits_per_sample(),
-                        0,
-                        0,
-                    )
-                    .unwrap(),
-            ),
+            format,
             flags: info.flags,
             framerate: info.framerate,
             num_frames: info.num_frames,
@@ -122,7 +882,9 @@ impl<'core> Filter<'core> for Mask<'core> {
         context: FrameContext,
         n: usize,
     ) -> Result<Option<FrameRef<'core>>, Error> {
-        self.source.request_frame_filter(context, n);
+        for i in self.temporal_range(n) {
+            self.source.request_frame_filter(context, i);
+        }
         Ok(None)
     }
 
@@ -133,95 +895,233 @@ impl<'core> Filter<'core> for Mask<'core> {
         context: FrameContext,
         n: usize,
     ) -> Result<FrameRef<'core>, Error> {
-        let new_format = from_property!(self.video_info(_api, core)[0].format);
+        let src_frame = self.get_source_frame(context, n)?;
+        // Format and resolution are taken from the frame to support variable clips.
+        let format = src_frame.format();
+        check_format(format)?;
+        let new_format = match self.output {
+            Some(output) => output,
+            None => mask_format(core, format)
+                .ok_or_else(|| format_err!("{}: could not register mask format", PLUGIN_NAME))?,
+        };
+        let prop_src = if self.propagate_props {
+            Some(&*src_frame)
+        } else {
+            None
+        };
         let mut frame = unsafe {
-            FrameRefMut::new_uninitialized(
-                core,
-                None,
-                new_format,
-                from_property!(self.source.info().resolution),
-            )
+            FrameRefMut::new_uninitialized(core, prop_src, new_format, src_frame.resolution(0))
         };
-        let src_frame = self.source.get_frame_filter(context, n).ok_or_else(|| {
-            format_err!("Could not retrieve source frame. This shouldn’t happen.")
+        let stats = PlaneStats::get(&src_frame)?;
+        let (range, transfer, range_depth) = self.luma_conversion(&src_frame);
+        // Only the average is cropped, clamping still depends on the min and max of the entire frame.
+        // The averages are converted to full range SDR per frame, since the props may differ between frames.
+        let current_average = self.average_cache.get(n, || {
+            if self.crop.is_empty() && !self.auto_crop && transfer == Transfer::Sdr {
+                Ok(range.to_full(stats.average, range_depth))
+            } else {
+                self.frame_average(&src_frame)
+            }
         })?;
-        let props = src_frame.props();
-        let average = match props.get::<f64>("PlaneStatsAverage") {
-            Ok(average) => average as f32,
-            Err(_) => bail!(format!(
-                "{}: you need to run std.PlaneStats on the clip before calling this function.",
-                PLUGIN_NAME
-            )),
+        let average = if self.temporal_radius == 0 {
+            current_average
+        } else {
+            let mut averages = Vec::with_capacity(2 * self.temporal_radius + 1);
+            for i in self.smoothing_range(context, n)? {
+                let average = if i == n {
+                    current_average
+                } else {
+                    self.average_cache.get(i, || {
+                        self.frame_average(&*self.get_source_frame(context, i)?)
+                    })?
+                };
+                averages.push((n.abs_diff(i), average));
+            }
+            temporal_average(&averages, self.temporal_decay)
         };
 
-        match from_property!(self.source.info().format).sample_type() {
+        let props = src_frame.props();
+        // Also accept integers, since `std.SetFrameProp(intval=...)` is easy to use by accident.
+        let luma_scaling = props
+            .get::<f64>(LUMA_SCALING_PROP)
+            .or_else(|_| props.get::<i64>(LUMA_SCALING_PROP).map(|ls| ls as f64))
+            .map_or(self.luma_scaling, |ls| ls as f32);
+        let luma_scaling = calc_luma_scaling(average, luma_scaling);
+        // Integer input uses a cached LUT, which is built for a slightly rounded luma scaling.
+        let luma_scaling = match format.sample_type() {
+            SampleType::Integer => cached_luma_scaling(luma_scaling, LUT_CACHE_MAX_ERROR),
+            SampleType::Float => luma_scaling,
+        };
+        let max = peak_value(new_format);
+        let output = (new_format.sample_type(), new_format.bytes_per_sample());
+        let (mut sum, clamped) = match format.sample_type() {
             SampleType::Integer => {
-                let depth = from_property!(self.source.info().format).bits_per_sample();
-                match depth {
-                    0..=8 => {
-                        int_filter!(u8, filter_8bit);
-                        filter_8bit(
-                            &mut frame,
-                            src_frame,
-                            depth,
-                            calc_luma_scaling(average, self.luma_scaling),
-                        )
-                    }
-                    9..=16 => {
-                        int_filter!(u16, filter_16bit);
-                        filter_16bit(
-                            &mut frame,
-                            src_frame,
-                            depth,
-                            calc_luma_scaling(average, self.luma_scaling),
-                        )
-                    }
-                    17..=32 => {
-                        int_filter!(u32, filter_32bit);
-                        filter_32bit(
-                            &mut frame,
-                            src_frame,
-                            depth,
-                            calc_luma_scaling(average, self.luma_scaling),
-                        )
-                    }
-                    _ => bail!(format!(
-                        "{}: input depth {} not supported",
-                        PLUGIN_NAME, depth
-                    )),
-                }
+                let key = LutKey {
+                    depth: format.bits_per_sample(),
+                    output_depth: new_format.bits_per_sample(),
+                    range,
+                    transfer,
+                };
+                let src_frame = &src_frame;
+                let frame = &mut frame;
+                let sum = match output {
+                    (SampleType::Integer, 1) => self.filter_int_to(
+                        frame,
+                        src_frame,
+                        &self.lut_cache_8bit,
+                        key,
+                        luma_scaling,
+                        max,
+                    ),
+                    (SampleType::Integer, 2) => self.filter_int_to(
+                        frame,
+                        src_frame,
+                        &self.lut_cache_16bit,
+                        key,
+                        luma_scaling,
+                        max,
+                    ),
+                    (SampleType::Integer, _) => self.filter_int_to(
+                        frame,
+                        src_frame,
+                        &self.lut_cache_32bit,
+                        key,
+                        luma_scaling,
+                        max,
+                    ),
+                    (SampleType::Float, 2) => self.filter_int_to(
+                        frame,
+                        src_frame,
+                        &self.lut_cache_half,
+                        key,
+                        luma_scaling,
+                        max,
+                    ),
+                    (SampleType::Float, _) => self.filter_int_to(
+                        frame,
+                        src_frame,
+                        &self.lut_cache_float,
+                        key,
+                        luma_scaling,
+                        max,
+                    ),
+                };
+                (sum, false)
             }
             SampleType::Float => {
                 // If the input has pixel values outside of the valid range (0-1),
                 // those might also be out of range in the output.
-                // We use the min/max props to determine if output clamping is necessary.
-                let max = props
-                    .get::<f64>("PlaneStatsMax")
-                    .expect(&format!("{}: no PlaneStatsMax in frame props", PLUGIN_NAME));
-                let min = props
-                    .get::<f64>("PlaneStatsMin")
-                    .expect(&format!("{}: no PlaneStatsMin in frame props", PLUGIN_NAME));
-                if max > 1.0 || min < 0.0 {
-                    filter_for_float_clamping(
-                        &mut frame,
-                        src_frame,
-                        calc_luma_scaling(average, self.luma_scaling),
-                    );
+                // We use the min/max stats to determine if output clamping is necessary.
+                // Limited range input also has to be clamped after converting it to full range.
+                let clamping = stats.max > 1.0 || stats.min < 0.0 || range == ColorRange::Limited;
+                let hdr = transfer != Transfer::Sdr;
+                let curve = &self.curve;
+                let mask_range = self.mask_range;
+                let fast = self.fast;
+                let direct_fn = |x: f32| {
+                    let x = if clamping || hdr {
+                        transfer.to_sdr(range.to_full(x, 8)).clamp(0.0, 1.0)
+                    } else {
+                        x
+                    };
+                    mask_range.apply(if fast {
+                        curve.mask_value_fast(x, luma_scaling)
+                    } else {
+                        curve.mask_value(x, luma_scaling)
+                    })
+                };
+                let lut = if self.interpolate {
+                    Some(InterpolatedLut::new(direct_fn))
                 } else {
-                    filter_for_float(
-                        &mut frame,
+                    None
+                };
+                let mask_fn = |x: f32| match &lut {
+                    Some(lut) => lut.get(x, direct_fn),
+                    None => direct_fn(x),
+                };
+                let simd = match (curve, hdr, range, self.interpolate) {
+                    (Curve::AdaptiveGrain, false, ColorRange::Full, false) => Some(SimdFloat {
+                        simd: self.simd,
+                        fast: self.fast,
+                        clamping,
+                        luma_scaling,
+                        mask_range,
+                    }),
+                    _ => None,
+                };
+                let src_frame = &src_frame;
+                let frame = &mut frame;
+                let sum = match output {
+                    (SampleType::Integer, 1) => filter_float_to::<u8>(
+                        frame,
                         src_frame,
-                        calc_luma_scaling(average, self.luma_scaling),
-                    );
-                }
+                        max,
+                        mask_fn,
+                        simd,
+                        self.pool.as_ref(),
+                    ),
+                    (SampleType::Integer, 2) => filter_float_to::<u16>(
+                        frame,
+                        src_frame,
+                        max,
+                        mask_fn,
+                        simd,
+                        self.pool.as_ref(),
+                    ),
+                    (SampleType::Integer, _) => filter_float_to::<u32>(
+                        frame,
+                        src_frame,
+                        max,
+                        mask_fn,
+                        simd,
+                        self.pool.as_ref(),
+                    ),
+                    (SampleType::Float, 2) => filter_float_to::<f16>(
+                        frame,
+                        src_frame,
+                        max,
+                        mask_fn,
+                        simd,
+                        self.pool.as_ref(),
+                    ),
+                    (SampleType::Float, _) => filter_float_to::<f32>(
+                        frame,
+                        src_frame,
+                        max,
+                        mask_fn,
+                        simd,
+                        self.pool.as_ref(),
+                    ),
+                };
+                (sum, clamping || hdr)
             }
+        };
+        // Every pass sums up the mask it writes, so the last one has the sum of the finished mask.
+        if self.detail_radius > 0 && self.detail_strength > 0.0 {
+            sum = self.attenuate_detail(&mut frame, &src_frame);
+        }
+        if self.blur_radius > 0 {
+            sum = self.blur(&mut frame);
         }
+        let mask_average = sum / (max as f64 * (frame.width(0) * frame.height(0)) as f64);
+        let mut props = frame.props_mut();
+        // The mask is a full range gray clip, whatever the source was.
+        props.set_int("_ColorRange", 0)?;
+        props.set_int("_Matrix", 2)?;
+        // The stats of the source don’t apply to the mask. They may not exist, so errors are ignored.
+        for key in &["PlaneStatsAverage", "PlaneStatsMin", "PlaneStatsMax"] {
+            let _ = props.delete_key(key);
+        }
+        props.set_float("AdgAverage", average as f64)?;
+        props.set_float("AdgEffectiveLumaScaling", luma_scaling as f64)?;
+        props.set_int("AdgClamped", clamped as i64)?;
+        props.set_float("AdgMaskAverage", mask_average)?;
         Ok(frame.into())
     }
 }
 
 pub fn calc_luma_scaling(average: f32, luma_scaling: f32) -> f32 {
-    let average = average.min(1.0).max(0.0);
+    let average = average.clamp(0.0, 1.0);
     average * average * luma_scaling
 }
 
@@ -265,6 +1165,334 @@ mod tests {
             });
     }
 
+    #[test]
+    fn test_lut_input() {
+        lut_input(8)
+            .zip(FLOAT_RANGE.iter())
+            .for_each(|(x, &exp)| assert!((x - exp).abs() < 0.0000001));
+        assert_eq!(lut_input(8).count(), 256);
+        assert_eq!(lut_input(10).count(), 1024);
+        assert_eq!(lut_input(16).count(), 65536);
+        assert_eq!(lut_input(32).count(), 65536);
+        assert_eq!(lut_input(10).last(), Some(1.0));
+    }
+
+    #[test]
+    fn test_mask_range() {
+        let default = MaskRange::default();
+        assert_eq!(default.apply(0.0), 0.0);
+        assert_eq!(default.apply(0.3), 0.3);
+        assert_eq!(default.apply(1.0), 1.0);
+        let range = MaskRange {
+            min: 0.2,
+            max: 0.6,
+            invert: false,
+        };
+        assert!((range.apply(0.0) - 0.2).abs() < 0.000001);
+        assert!((range.apply(0.5) - 0.4).abs() < 0.000001);
+        assert!((range.apply(1.0) - 0.6).abs() < 0.000001);
+        let inverted = MaskRange {
+            invert: true,
+            ..range
+        };
+        assert!((inverted.apply(0.0) - 0.6).abs() < 0.000001);
+        assert!((inverted.apply(0.25) - 0.5).abs() < 0.000001);
+        assert!((inverted.apply(1.0) - 0.2).abs() < 0.000001);
+        for &range in &[default, range, inverted] {
+            for &x in &[0.0, 0.3, 1.0] {
+                assert!((range.unapply(range.apply(x)) - x).abs() < 0.000001);
+            }
+        }
+        let constant = MaskRange {
+            min: 0.4,
+            max: 0.4,
+            invert: false,
+        };
+        assert_eq!(constant.apply(constant.unapply(0.4)), 0.4);
+    }
+
+    #[test]
+    fn test_attenuate() {
+        let default = MaskRange::default();
+        assert_eq!(default.attenuate(0.8, 0.0, 0.5), 0.8);
+        assert!((default.attenuate(0.8, DETAIL_SATURATION, 0.5) - 0.4).abs() < 0.000001);
+        assert!((default.attenuate(0.8, DETAIL_SATURATION / 2.0, 0.5) - 0.6).abs() < 0.000001);
+        // Textured areas don’t drop below min
+        let range = MaskRange {
+            min: 0.3,
+            max: 1.0,
+            invert: false,
+        };
+        assert!((range.attenuate(1.0, 1.0, 1.0) - 0.3).abs() < 0.000001);
+        assert!((range.attenuate(range.apply(0.5), 1.0, 0.5) - range.apply(0.25)).abs() < 0.000001);
+        // Inverted masks get larger where the curve value is reduced
+        let inverted = MaskRange {
+            invert: true,
+            ..MaskRange::default()
+        };
+        assert!((inverted.attenuate(0.2, 1.0, 0.5) - 0.6).abs() < 0.000001);
+        assert_eq!(inverted.attenuate(1.0, 1.0, 1.0), 1.0);
+    }
+
+    #[test]
+    fn test_mask_sample() {
+        assert_eq!(u8::from_mask(1.0, 255.0), 255);
+        assert_eq!(u8::from_mask(0.5, 255.0), 127);
+        assert_eq!(u16::from_mask(1.0, 1023.0), 1023);
+        assert_eq!(u32::from_mask(0.0, 4294967295.0), 0);
+        assert_eq!(f16::from_mask(0.5, 1.0), f16::from_f32(0.5));
+        assert_eq!(f32::from_mask(0.123, 1.0), 0.123);
+    }
+
+    #[test]
+    fn test_interpolated_lut() {
+        use crate::grain::Rng;
+
+        let mut rng = Rng::new(1, 0);
+        let mut samples: Vec<f32> = (0..100_000)
+            .map(|_| rng.next_gaussian() * 0.4 + 0.5)
+            .collect();
+        samples.extend_from_slice(&FLOAT_RANGE);
+        samples.extend_from_slice(&[-1.0, 0.0, 1.0, 2.0, 1.0 - f32::EPSILON]);
+        let curves = [
+            Curve::AdaptiveGrain,
+            Curve::from_name("gamma", &[0.5], None).unwrap(),
+            Curve::from_name("sigmoid", &[0.3, 20.0], None).unwrap(),
+            Curve::from_name("linear", &[0.1, 1.0, 0.5, 0.8, 0.9, 0.0], None).unwrap(),
+        ];
+        // HDR input isn’t tested because the f32 PQ and HLG conversions alone are noisier than that.
+        for curve in &curves {
+            for &luma_scaling in &[0.01, 0.1, 0.4, 1.0, 4.0, 10.0, 100.0] {
+                let mask_fn = |x: f32| curve.mask_value_clamping(x, luma_scaling);
+                let lut = InterpolatedLut::new(mask_fn);
+                for &x in &samples {
+                    let expected = mask_fn(x);
+                    let value = lut.get(x, mask_fn);
+                    assert!(
+                        (value - expected).abs() <= INTERPOLATION_MAX_ERROR,
+                        "{:?} with luma scaling {} was wrong at {}, expected {}, got {}",
+                        curve,
+                        luma_scaling,
+                        x,
+                        expected,
+                        value
+                    );
+                }
+            }
+        }
+        // Only the steep part close to white has to be evaluated directly
+        let lut = InterpolatedLut::new(|x| get_mask_value(x, 0.1));
+        assert!(lut.exact_cells() < INTERPOLATION_STEPS / 50);
+        let lut = InterpolatedLut::new(|x| get_mask_value(x, 4.0));
+        assert_eq!(lut.exact_cells(), 0);
+    }
+
+    #[test]
+    fn test_process_stripes() {
+        // 10 rows don’t divide evenly into 3 or 4 stripes
+        let fill = |pool: Option<&ThreadPool>| {
+            let mut plane = vec![0u16; 7 * 10];
+            let mut rows: Vec<&mut [u16]> = plane.chunks_mut(7).collect();
+            let sum = process_stripes(&mut rows, pool, |first, rows| {
+                for (i, row) in rows.iter_mut().enumerate() {
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        *pixel = ((first + i) * 100 + x) as u16;
+                    }
+                }
+                rows.iter().map(|row| row_sum(row)).sum()
+            });
+            // 10 * (0 + ... + 6) + 7 * 100 * (0 + ... + 9)
+            assert_eq!(sum, 31710.0);
+            plane
+        };
+        let serial = fill(None);
+        assert_eq!(serial[3 * 7 + 2], 302);
+        for &threads in &[1, 3, 4, 16] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            assert_eq!(fill(Some(&pool)), serial);
+        }
+    }
+
+    #[test]
+    fn test_lut_cache_error() {
+        use crate::grain::Rng;
+
+        let mut rng = Rng::new(2, 0);
+        let curves = [
+            Curve::AdaptiveGrain,
+            Curve::from_name("gamma", &[0.5], None).unwrap(),
+            Curve::from_name("sigmoid", &[0.3, 20.0], None).unwrap(),
+        ];
+        for _ in 0..800 {
+            let ls = calc_luma_scaling(rng.next_gaussian().abs() * 0.3, 10.0);
+            let cached = cached_luma_scaling(ls, LUT_CACHE_MAX_ERROR);
+            for curve in &curves {
+                for &x in &FLOAT_RANGE {
+                    let error = (curve.mask_value(x, ls) - curve.mask_value(x, cached)).abs();
+                    assert!(
+                        error <= LUT_CACHE_MAX_ERROR * 1.01 + 1e-7,
+                        "{} at {}",
+                        ls,
+                        x
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_lut_cache_hit_rate() {
+        use crate::grain::Rng;
+
+        // Frame averages that drift by about 1e-3 per frame, with a scene change every 100 frames
+        let mut rng = Rng::new(4, 0);
+        let mut average: f32 = 0.4;
+        let averages: Vec<f32> = (0..2000)
+            .map(|i| {
+                if i % 100 == 0 {
+                    average = 0.1 + (rng.next_gaussian().abs() * 0.3).min(0.6);
+                }
+                average = (average + rng.next_gaussian() * 0.001).clamp(0.01, 1.0);
+                average
+            })
+            .collect();
+        let cache = LutCache::default();
+        let key = LutKey {
+            depth: 16,
+            output_depth: 16,
+            range: ColorRange::Full,
+            transfer: Transfer::Sdr,
+        };
+        let mut builds = 0;
+        for &average in &averages {
+            let ls = calc_luma_scaling(average, 10.0);
+            cache.get(key, ls, LUT_CACHE_MAX_ERROR, |_| {
+                builds += 1;
+                vec![0u16]
+            });
+        }
+        // Most frames reuse a LUT, even for 16-bit masks
+        assert!(builds * 4 < averages.len());
+    }
+
+    #[test]
+    fn test_local_deviation() {
+        // Flat left half, alternating 0/1 columns on the right half
+        let (width, height) = (8, 4);
+        let values: Vec<f32> = (0..width * height)
+            .map(|i| match i % width {
+                x if x < 4 => 0.5,
+                x => (x % 2) as f32,
+            })
+            .collect();
+        let deviation = |radius| {
+            let mut deviation = Vec::new();
+            local_deviation(
+                width,
+                height,
+                radius,
+                |y, row| row.copy_from_slice(&values[y * width..(y + 1) * width]),
+                |y, row| {
+                    assert_eq!(y * width, deviation.len());
+                    deviation.extend_from_slice(row);
+                },
+            );
+            deviation
+        };
+        let radius_1 = deviation(1);
+        assert_eq!(radius_1.len(), values.len());
+        assert!(radius_1[0].abs() < 0.00001);
+        assert!(radius_1[width + 1].abs() < 0.00001);
+        // Three columns 0, 1, 0 have a standard deviation of sqrt(2) / 3
+        assert!((radius_1[width + 5] - 2f32.sqrt() / 3.0).abs() < 0.00001);
+        // Radius 0 only sees the pixel itself
+        assert!(deviation(0).iter().all(|&d| d.abs() < 0.00001));
+        // A window larger than the plane covers all of it
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+        assert!(deviation(10)
+            .iter()
+            .all(|&d| (d - variance.sqrt()).abs() < 0.00001));
+    }
+
+    #[test]
+    fn test_box_blur() {
+        let blur = |values: &[f32], width: usize, radius: usize| {
+            let mut blurred = values.to_vec();
+            let mut rows: Vec<&mut [f32]> = blurred.chunks_mut(width).collect();
+            box_blur(&mut rows, 1.0, radius);
+            blurred
+        };
+        let (width, height) = (5, 3);
+        let mut values = vec![0.0; width * height];
+        values[width + 2] = 0.9;
+        let blurred = blur(&values, width, 1);
+        assert!((blurred[width + 2] - 0.1).abs() < 0.00001);
+        // Cropped to 2x2 in the corner
+        assert!((blurred[width + 3] - 0.1).abs() < 0.00001);
+        assert!((blurred[1] - 0.15).abs() < 0.00001);
+        assert_eq!(blurred[0], 0.0);
+        // A constant plane is unchanged
+        assert_eq!(blur(&[0.5; 15], width, 2), vec![0.5; 15]);
+        assert_eq!(blur(&values, width, 0), values);
+
+        // Same as averaging every window directly
+        let (width, height) = (13, 9);
+        let mut rng = crate::grain::Rng::new(3, 0);
+        let values: Vec<f32> = (0..width * height).map(|_| rng.next_gaussian()).collect();
+        for &radius in &[1, 2, 4, 20] {
+            let blurred = blur(&values, width, radius);
+            for y in 0..height {
+                for x in 0..width {
+                    let rows = y.saturating_sub(radius)..(y + radius + 1).min(height);
+                    let columns = x.saturating_sub(radius)..(x + radius + 1).min(width);
+                    let count = (rows.len() * columns.len()) as f32;
+                    let mean = rows
+                        .flat_map(|y| values[y * width..][columns.clone()].iter())
+                        .sum::<f32>()
+                        / count;
+                    assert!((blurred[y * width + x] - mean).abs() < 0.0001);
+                }
+            }
+        }
+        // Integer samples are rounded
+        let mut plane = [0u8, 0, 255, 0];
+        let mut rows: Vec<&mut [u8]> = plane.chunks_mut(4).collect();
+        // The sum of the blurred samples is returned for the mask average
+        assert_eq!(box_blur(&mut rows, 255.0, 1), 298.0);
+        assert_eq!(plane, [0, 85, 85, 128]);
+    }
+
+    #[test]
+    fn test_mask_sample_roundtrip() {
+        for i in 0..=255u8 {
+            assert_eq!(u8::from_mask_rounded(i.to_mask(255.0), 255.0), i);
+        }
+        assert_eq!(u16::from_mask_rounded(1.5, 1023.0), 1023);
+        assert_eq!(f32::from_mask_rounded(0.3.to_mask(1.0), 1.0), 0.3);
+    }
+
+    #[test]
+    fn test_lookup_row_clamping() {
+        // 10-bit input with values above 1023 gets the last entry on every CPU
+        let src = [0u16, 1023, 1024, 4000];
+        let lut: Vec<f16> = (0..1024)
+            .map(|i| f16::from_f32(i as f32 / 1023.0))
+            .collect();
+        let mut dst = [f16::from_f32(0.5); 4];
+        f16::lookup_row(SimdLevel::Scalar, &src, &lut, 0, &mut dst);
+        assert_eq!(dst.map(f16::to_f32), [0.0, 1.0, 1.0, 1.0]);
+        let lut: Vec<u16> = (0..1024).collect();
+        for level in [SimdLevel::Scalar, SimdLevel::Sse2, SimdLevel::detect()] {
+            let mut dst = [0u16; 4];
+            u16::lookup_row(level, &src, &lut, 0, &mut dst);
+            assert_eq!(dst, [0, 1023, 1023, 1023]);
+        }
+    }
+
     #[test]
     fn test_mask_values_clamping() {
         FLOAT_RANGE
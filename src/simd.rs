@@ -0,0 +1,853 @@
+//! Hand-vectorized kernels for x86_64 that are selected at runtime,
+//! so the same binary can use FMA and wide registers where they exist.
+//! All kernels fall back to scalar code for the remainder of a row and on other architectures.
+use super::mask::get_mask_base;
+
+/// The instruction sets that the kernels are available for, from worst to best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+    Scalar,
+    Sse2,
+    /// AVX2 and FMA
+    Avx2,
+    /// AVX-512F
+    Avx512,
+}
+
+impl SimdLevel {
+    /// The best level that the current CPU supports.
+    #[cfg(target_arch = "x86_64")]
+    pub fn detect() -> Self {
+        if is_x86_feature_detected!("avx512f") {
+            SimdLevel::Avx512
+        } else if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            SimdLevel::Avx2
+        } else {
+            // Part of x86_64
+            SimdLevel::Sse2
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn detect() -> Self {
+        SimdLevel::Scalar
+    }
+
+    /// Never more than the CPU supports, so that the kernels can be called safely with any level.
+    #[inline]
+    fn supported(self) -> Self {
+        self.min(SimdLevel::detect())
+    }
+}
+
+/// Writes `get_mask_base` of every value in `src` to `dst`, optionally clamping the input to 0-1.
+/// The result is never negative, even where rounding would make it so (just below white without FMA).
+pub fn mask_base(level: SimdLevel, src: &[f32], dst: &mut [f32], clamp: bool) {
+    let done = match level.supported() {
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx512 => unsafe { x86::mask_base_avx512(src, dst, clamp) },
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx2 => unsafe { x86::mask_base_avx2(src, dst, clamp) },
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Sse2 => unsafe { x86::mask_base_sse2(src, dst, clamp) },
+        _ => 0,
+    };
+    for (base, &x) in dst[done..].iter_mut().zip(&src[done..]) {
+        *base = get_mask_base(if clamp { x.clamp(0.0, 1.0) } else { x }).max(0.0);
+    }
+}
+
+/// The maximum absolute error of `fast_pow` for x in (0, 1] and positive y, which is below one 16-bit step.
+pub const FAST_POW_MAX_ERROR: f32 = 1.0 / 65535.0;
+
+/// 2 / ln(2) / (2k + 1), for log2(m) = (2 / ln(2)) * atanh(s) with s = (m - 1) / (m + 1).
+const LOG2_COEFFICIENTS: [f32; 5] = [2.885_39, 0.961_796_7, 0.577_078, 0.412_198_6, 0.320_598_9];
+/// ln(2)^k / k!, the Taylor series of 2^f.
+const EXP2_COEFFICIENTS: [f32; 7] = [
+    1.0,
+    std::f32::consts::LN_2,
+    0.240_226_5,
+    0.055_504_11,
+    0.009_618_129,
+    0.001_333_355_8,
+    0.000_154_035_3,
+];
+
+/// An approximation of `x.powf(y)` as 2^(y * log2(x)) that can be vectorized.
+/// Accurate to `FAST_POW_MAX_ERROR` for the inputs that the mask needs, i.e. x in [0, 1] and y >= 0.
+#[inline]
+pub fn fast_pow(x: f32, y: f32) -> f32 {
+    if y == 0.0 {
+        return 1.0;
+    }
+    if x.is_nan() || x <= 0.0 {
+        return 0.0;
+    }
+    // x = m * 2^e with m in [sqrt(0.5), sqrt(2)]
+    let bits = x.to_bits();
+    let mut e = ((bits >> 23) & 0xff) as i32 - 127;
+    let mut m = f32::from_bits((bits & 0x007f_ffff) | 0x3f80_0000);
+    if m > std::f32::consts::SQRT_2 {
+        m *= 0.5;
+        e += 1;
+    }
+    let s = (m - 1.0) / (m + 1.0);
+    let s2 = s * s;
+    let p = LOG2_COEFFICIENTS
+        .iter()
+        .rev()
+        .fold(0.0, |acc: f32, &c| acc * s2 + c);
+    let t = (y * (s * p + e as f32)).clamp(-126.0, 127.0);
+    // 2^t = 2^i * 2^f with f in [-0.5, 0.5]
+    let i = t.round();
+    let f = t - i;
+    let p = EXP2_COEFFICIENTS
+        .iter()
+        .rev()
+        .fold(0.0, |acc: f32, &c| acc * f + c);
+    p * f32::from_bits(((i as i32 + 127) as u32) << 23)
+}
+
+/// Writes an approximation of `get_mask_value` for every value in `src` to `dst`,
+/// optionally clamping the input to 0-1. See `fast_pow` for the accuracy.
+pub fn mask_value_fast(
+    level: SimdLevel,
+    src: &[f32],
+    dst: &mut [f32],
+    clamp: bool,
+    luma_scaling: f32,
+) {
+    // Anything to the power of 0 is 1, which the kernels don’t handle.
+    if luma_scaling == 0.0 {
+        dst.iter_mut().for_each(|v| *v = 1.0);
+        return;
+    }
+    let done = match level.supported() {
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx512 => unsafe { x86::mask_value_fast_avx512(src, dst, clamp, luma_scaling) },
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx2 => unsafe { x86::mask_value_fast_avx2(src, dst, clamp, luma_scaling) },
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Sse2 => unsafe { x86::mask_value_fast_sse2(src, dst, clamp, luma_scaling) },
+        _ => 0,
+    };
+    for (value, &x) in dst[done..].iter_mut().zip(&src[done..]) {
+        let x = if clamp { x.clamp(0.0, 1.0) } else { x };
+        *value = fast_pow(get_mask_base(x).max(0.0), luma_scaling);
+    }
+}
+
+/// An integer sample type that can be used as a LUT index.
+pub trait LutIndex: Copy + Into<u64> {
+    /// Looks up as many values as possible with a vectorized gather and returns how many that were.
+    /// Indices past the end of the LUT are clamped to the last entry.
+    fn gather_u32(level: SimdLevel, src: &[Self], lut: &[u32], shift: u8, dst: &mut [u32])
+        -> usize;
+    /// `gather_u32` for LUTs with 16-bit entries.
+    fn gather_u16(level: SimdLevel, src: &[Self], lut: &[u16], shift: u8, dst: &mut [u16])
+        -> usize;
+    /// `gather_u32` for LUTs with 8-bit entries.
+    fn gather_u8(level: SimdLevel, src: &[Self], lut: &[u8], shift: u8, dst: &mut [u8]) -> usize;
+}
+
+macro_rules! lut_index_method {
+    ($method:ident, $entry:ty, $avx2:ident, $avx512:ident) => {
+        #[inline]
+        fn $method(
+            level: SimdLevel,
+            src: &[Self],
+            lut: &[$entry],
+            shift: u8,
+            dst: &mut [$entry],
+        ) -> usize {
+            match level.supported() {
+                #[cfg(target_arch = "x86_64")]
+                SimdLevel::Avx512 => unsafe { x86::$avx512(src, lut, shift, dst) },
+                #[cfg(target_arch = "x86_64")]
+                SimdLevel::Avx2 => unsafe { x86::$avx2(src, lut, shift, dst) },
+                // There is no gather before AVX2.
+                _ => 0,
+            }
+        }
+    };
+}
+
+macro_rules! lut_index {
+    ($type:ty, [$($method:ident, $entry:ty, $avx2:ident, $avx512:ident);*]) => {
+        impl LutIndex for $type {
+            $(lut_index_method!($method, $entry, $avx2, $avx512);)*
+        }
+    };
+}
+
+lut_index!(u8, [
+    gather_u32, u32, gather_u8_u32_avx2, gather_u8_u32_avx512;
+    gather_u16, u16, gather_u8_u16_avx2, gather_u8_u16_avx512;
+    gather_u8, u8, gather_u8_u8_avx2, gather_u8_u8_avx512
+]);
+lut_index!(u16, [
+    gather_u32, u32, gather_u16_u32_avx2, gather_u16_u32_avx512;
+    gather_u16, u16, gather_u16_u16_avx2, gather_u16_u16_avx512;
+    gather_u8, u8, gather_u16_u8_avx2, gather_u16_u8_avx512
+]);
+lut_index!(u32, [
+    gather_u32, u32, gather_u32_u32_avx2, gather_u32_u32_avx512;
+    gather_u16, u16, gather_u32_u16_avx2, gather_u32_u16_avx512;
+    gather_u8, u8, gather_u32_u8_avx2, gather_u32_u8_avx512
+]);
+
+/// The type of LUT entries that can be gathered.
+pub trait LutEntry: Copy {
+    fn gather_simd<T: LutIndex>(
+        level: SimdLevel,
+        src: &[T],
+        lut: &[Self],
+        shift: u8,
+        dst: &mut [Self],
+    ) -> usize;
+}
+
+macro_rules! lut_entry {
+    ($type:ty, $method:ident) => {
+        impl LutEntry for $type {
+            #[inline]
+            fn gather_simd<T: LutIndex>(
+                level: SimdLevel,
+                src: &[T],
+                lut: &[Self],
+                shift: u8,
+                dst: &mut [Self],
+            ) -> usize {
+                T::$method(level, src, lut, shift, dst)
+            }
+        }
+    };
+}
+
+lut_entry!(u8, gather_u8);
+lut_entry!(u16, gather_u16);
+lut_entry!(u32, gather_u32);
+
+/// Looks up `src >> shift` in a LUT.
+pub fn gather<T: LutIndex, E: LutEntry>(
+    level: SimdLevel,
+    src: &[T],
+    lut: &[E],
+    shift: u8,
+    dst: &mut [E],
+) {
+    let done = E::gather_simd(level, src, lut, shift, dst);
+    // Clamped like in the vectorized kernels, so out-of-range input gives the same result on every CPU
+    let last = lut.len() - 1;
+    for (pixel, &src_pixel) in dst[done..].iter_mut().zip(&src[done..]) {
+        *pixel = lut[((src_pixel.into() >> shift) as usize).min(last)];
+    }
+}
+
+/// `gather` for float LUTs, which are gathered as their bit patterns.
+pub fn gather_f32<T: LutIndex>(
+    level: SimdLevel,
+    src: &[T],
+    lut: &[f32],
+    shift: u8,
+    dst: &mut [f32],
+) {
+    // Same size and alignment, and every bit pattern is a valid u32.
+    let lut = unsafe { std::slice::from_raw_parts(lut.as_ptr() as *const u32, lut.len()) };
+    let dst = unsafe { std::slice::from_raw_parts_mut(dst.as_mut_ptr() as *mut u32, dst.len()) };
+    gather(level, src, lut, shift, dst);
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::{EXP2_COEFFICIENTS, LOG2_COEFFICIENTS};
+    use std::f32::consts::SQRT_2;
+
+    /// Coefficients of the polynomial in `get_mask_base`, highest degree first.
+    const COEFFICIENTS: [f32; 5] = [18.188, -45.47, 36.624, -9.466, 1.124];
+
+    // The same evaluation order as the scalar version, but without FMA.
+    #[target_feature(enable = "sse2")]
+    unsafe fn base_sse2(x: __m128, clamp: bool) -> __m128 {
+        let one = _mm_set1_ps(1.0);
+        let x = if clamp {
+            _mm_min_ps(_mm_max_ps(x, _mm_setzero_ps()), one)
+        } else {
+            x
+        };
+        let mut p = _mm_add_ps(
+            _mm_mul_ps(x, _mm_set1_ps(COEFFICIENTS[0])),
+            _mm_set1_ps(COEFFICIENTS[1]),
+        );
+        for &c in &COEFFICIENTS[2..] {
+            p = _mm_add_ps(_mm_mul_ps(x, p), _mm_set1_ps(c));
+        }
+        // Without FMA, this can round to slightly below 0 close to white
+        _mm_max_ps(_mm_sub_ps(one, _mm_mul_ps(x, p)), _mm_setzero_ps())
+    }
+
+    /// `fast_pow` for 4 values with positive `y`.
+    #[target_feature(enable = "sse2")]
+    unsafe fn pow_sse2(x: __m128, y: __m128) -> __m128 {
+        let one = _mm_set1_ps(1.0);
+        // log2
+        let bits = _mm_castps_si128(x);
+        let mut e = _mm_sub_epi32(_mm_srli_epi32::<23>(bits), _mm_set1_epi32(127));
+        let mut m = _mm_castsi128_ps(_mm_or_si128(
+            _mm_and_si128(bits, _mm_set1_epi32(0x007f_ffff)),
+            _mm_set1_epi32(0x3f80_0000),
+        ));
+        let big = _mm_cmpgt_ps(m, _mm_set1_ps(SQRT_2));
+        m = _mm_mul_ps(
+            m,
+            _mm_or_ps(_mm_and_ps(big, _mm_set1_ps(0.5)), _mm_andnot_ps(big, one)),
+        );
+        // The mask is -1 where m was halved
+        e = _mm_sub_epi32(e, _mm_castps_si128(big));
+        let s = _mm_div_ps(_mm_sub_ps(m, one), _mm_add_ps(m, one));
+        let s2 = _mm_mul_ps(s, s);
+        let mut p = _mm_set1_ps(LOG2_COEFFICIENTS[4]);
+        for &c in LOG2_COEFFICIENTS[..4].iter().rev() {
+            p = _mm_add_ps(_mm_mul_ps(p, s2), _mm_set1_ps(c));
+        }
+        let log2 = _mm_add_ps(_mm_cvtepi32_ps(e), _mm_mul_ps(s, p));
+        // exp2
+        let t = _mm_mul_ps(y, log2);
+        let t = _mm_max_ps(_mm_min_ps(t, _mm_set1_ps(127.0)), _mm_set1_ps(-126.0));
+        let i = _mm_cvtps_epi32(t);
+        let f = _mm_sub_ps(t, _mm_cvtepi32_ps(i));
+        let mut p = _mm_set1_ps(EXP2_COEFFICIENTS[6]);
+        for &c in EXP2_COEFFICIENTS[..6].iter().rev() {
+            p = _mm_add_ps(_mm_mul_ps(p, f), _mm_set1_ps(c));
+        }
+        let scale = _mm_castsi128_ps(_mm_slli_epi32::<23>(_mm_add_epi32(i, _mm_set1_epi32(127))));
+        // 0 (and NaN) to the power of a positive y is 0
+        _mm_and_ps(_mm_mul_ps(p, scale), _mm_cmpgt_ps(x, _mm_setzero_ps()))
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn base_avx2(x: __m256, clamp: bool) -> __m256 {
+        let one = _mm256_set1_ps(1.0);
+        let x = if clamp {
+            _mm256_min_ps(_mm256_max_ps(x, _mm256_setzero_ps()), one)
+        } else {
+            x
+        };
+        let mut p = _mm256_fmadd_ps(
+            x,
+            _mm256_set1_ps(COEFFICIENTS[0]),
+            _mm256_set1_ps(COEFFICIENTS[1]),
+        );
+        for &c in &COEFFICIENTS[2..] {
+            p = _mm256_fmadd_ps(x, p, _mm256_set1_ps(c));
+        }
+        _mm256_max_ps(_mm256_sub_ps(one, _mm256_mul_ps(x, p)), _mm256_setzero_ps())
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn pow_avx2(x: __m256, y: __m256) -> __m256 {
+        let one = _mm256_set1_ps(1.0);
+        let bits = _mm256_castps_si256(x);
+        let mut e = _mm256_sub_epi32(_mm256_srli_epi32::<23>(bits), _mm256_set1_epi32(127));
+        let mut m = _mm256_castsi256_ps(_mm256_or_si256(
+            _mm256_and_si256(bits, _mm256_set1_epi32(0x007f_ffff)),
+            _mm256_set1_epi32(0x3f80_0000),
+        ));
+        let big = _mm256_cmp_ps::<_CMP_GT_OQ>(m, _mm256_set1_ps(SQRT_2));
+        m = _mm256_blendv_ps(m, _mm256_mul_ps(m, _mm256_set1_ps(0.5)), big);
+        e = _mm256_sub_epi32(e, _mm256_castps_si256(big));
+        let s = _mm256_div_ps(_mm256_sub_ps(m, one), _mm256_add_ps(m, one));
+        let s2 = _mm256_mul_ps(s, s);
+        let mut p = _mm256_set1_ps(LOG2_COEFFICIENTS[4]);
+        for &c in LOG2_COEFFICIENTS[..4].iter().rev() {
+            p = _mm256_fmadd_ps(p, s2, _mm256_set1_ps(c));
+        }
+        let log2 = _mm256_fmadd_ps(s, p, _mm256_cvtepi32_ps(e));
+        let t = _mm256_mul_ps(y, log2);
+        let t = _mm256_max_ps(
+            _mm256_min_ps(t, _mm256_set1_ps(127.0)),
+            _mm256_set1_ps(-126.0),
+        );
+        let i = _mm256_cvtps_epi32(t);
+        let f = _mm256_sub_ps(t, _mm256_cvtepi32_ps(i));
+        let mut p = _mm256_set1_ps(EXP2_COEFFICIENTS[6]);
+        for &c in EXP2_COEFFICIENTS[..6].iter().rev() {
+            p = _mm256_fmadd_ps(p, f, _mm256_set1_ps(c));
+        }
+        let scale = _mm256_castsi256_ps(_mm256_slli_epi32::<23>(_mm256_add_epi32(
+            i,
+            _mm256_set1_epi32(127),
+        )));
+        _mm256_and_ps(
+            _mm256_mul_ps(p, scale),
+            _mm256_cmp_ps::<_CMP_GT_OQ>(x, _mm256_setzero_ps()),
+        )
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn base_avx512(x: __m512, clamp: bool) -> __m512 {
+        let one = _mm512_set1_ps(1.0);
+        let x = if clamp {
+            _mm512_min_ps(_mm512_max_ps(x, _mm512_setzero_ps()), one)
+        } else {
+            x
+        };
+        let mut p = _mm512_fmadd_ps(
+            x,
+            _mm512_set1_ps(COEFFICIENTS[0]),
+            _mm512_set1_ps(COEFFICIENTS[1]),
+        );
+        for &c in &COEFFICIENTS[2..] {
+            p = _mm512_fmadd_ps(x, p, _mm512_set1_ps(c));
+        }
+        _mm512_max_ps(_mm512_sub_ps(one, _mm512_mul_ps(x, p)), _mm512_setzero_ps())
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn pow_avx512(x: __m512, y: __m512) -> __m512 {
+        let one = _mm512_set1_ps(1.0);
+        let bits = _mm512_castps_si512(x);
+        let mut e = _mm512_sub_epi32(_mm512_srli_epi32::<23>(bits), _mm512_set1_epi32(127));
+        let mut m = _mm512_castsi512_ps(_mm512_or_si512(
+            _mm512_and_si512(bits, _mm512_set1_epi32(0x007f_ffff)),
+            _mm512_set1_epi32(0x3f80_0000),
+        ));
+        let big = _mm512_cmp_ps_mask::<_CMP_GT_OQ>(m, _mm512_set1_ps(SQRT_2));
+        m = _mm512_mask_mul_ps(m, big, m, _mm512_set1_ps(0.5));
+        e = _mm512_mask_add_epi32(e, big, e, _mm512_set1_epi32(1));
+        let s = _mm512_div_ps(_mm512_sub_ps(m, one), _mm512_add_ps(m, one));
+        let s2 = _mm512_mul_ps(s, s);
+        let mut p = _mm512_set1_ps(LOG2_COEFFICIENTS[4]);
+        for &c in LOG2_COEFFICIENTS[..4].iter().rev() {
+            p = _mm512_fmadd_ps(p, s2, _mm512_set1_ps(c));
+        }
+        let log2 = _mm512_fmadd_ps(s, p, _mm512_cvtepi32_ps(e));
+        let t = _mm512_mul_ps(y, log2);
+        let t = _mm512_max_ps(
+            _mm512_min_ps(t, _mm512_set1_ps(127.0)),
+            _mm512_set1_ps(-126.0),
+        );
+        let i = _mm512_cvtps_epi32(t);
+        let f = _mm512_sub_ps(t, _mm512_cvtepi32_ps(i));
+        let mut p = _mm512_set1_ps(EXP2_COEFFICIENTS[6]);
+        for &c in EXP2_COEFFICIENTS[..6].iter().rev() {
+            p = _mm512_fmadd_ps(p, f, _mm512_set1_ps(c));
+        }
+        let scale = _mm512_castsi512_ps(_mm512_slli_epi32::<23>(_mm512_add_epi32(
+            i,
+            _mm512_set1_epi32(127),
+        )));
+        let positive = _mm512_cmp_ps_mask::<_CMP_GT_OQ>(x, _mm512_setzero_ps());
+        _mm512_maskz_mul_ps(positive, p, scale)
+    }
+
+    /// Generates the row kernels for one instruction set.
+    /// They return how many values they processed, the caller does the rest.
+    macro_rules! float_kernels {
+        ($feature:literal, $width:literal, $load:ident, $store:ident, $set1:ident, $base:ident, $pow:ident,
+         $mask_base:ident, $mask_value_fast:ident) => {
+            #[target_feature(enable = $feature)]
+            pub unsafe fn $mask_base(src: &[f32], dst: &mut [f32], clamp: bool) -> usize {
+                let n = src.len().min(dst.len()) / $width * $width;
+                for i in (0..n).step_by($width) {
+                    let x = $load(src.as_ptr().add(i));
+                    $store(dst.as_mut_ptr().add(i), $base(x, clamp));
+                }
+                n
+            }
+
+            #[target_feature(enable = $feature)]
+            pub unsafe fn $mask_value_fast(
+                src: &[f32],
+                dst: &mut [f32],
+                clamp: bool,
+                luma_scaling: f32,
+            ) -> usize {
+                let n = src.len().min(dst.len()) / $width * $width;
+                let y = $set1(luma_scaling);
+                for i in (0..n).step_by($width) {
+                    let x = $load(src.as_ptr().add(i));
+                    $store(dst.as_mut_ptr().add(i), $pow($base(x, clamp), y));
+                }
+                n
+            }
+        };
+    }
+
+    float_kernels!(
+        "sse2",
+        4,
+        _mm_loadu_ps,
+        _mm_storeu_ps,
+        _mm_set1_ps,
+        base_sse2,
+        pow_sse2,
+        mask_base_sse2,
+        mask_value_fast_sse2
+    );
+    float_kernels!(
+        "avx2,fma",
+        8,
+        _mm256_loadu_ps,
+        _mm256_storeu_ps,
+        _mm256_set1_ps,
+        base_avx2,
+        pow_avx2,
+        mask_base_avx2,
+        mask_value_fast_avx2
+    );
+    float_kernels!(
+        "avx512f",
+        16,
+        _mm512_loadu_ps,
+        _mm512_storeu_ps,
+        _mm512_set1_ps,
+        base_avx512,
+        pow_avx512,
+        mask_base_avx512,
+        mask_value_fast_avx512
+    );
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn store_u32_avx2(dst: *mut u32, values: __m256i) {
+        _mm256_storeu_si256(dst as *mut __m256i, values);
+    }
+
+    // The packs work within 128-bit lanes, so the results of both lanes are moved together.
+    #[target_feature(enable = "avx2")]
+    unsafe fn pack_u16_avx2(values: __m256i) -> __m128i {
+        let packed = _mm256_permute4x64_epi64::<0b1000>(_mm256_packus_epi32(values, values));
+        _mm256_castsi256_si128(packed)
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn store_u16_avx2(dst: *mut u16, values: __m256i) {
+        _mm_storeu_si128(dst as *mut __m128i, pack_u16_avx2(values));
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn store_u8_avx2(dst: *mut u8, values: __m256i) {
+        let packed = pack_u16_avx2(values);
+        _mm_storel_epi64(dst as *mut __m128i, _mm_packus_epi16(packed, packed));
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn store_u32_avx512(dst: *mut u32, values: __m512i) {
+        _mm512_storeu_si512(dst as *mut __m512i, values);
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn store_u16_avx512(dst: *mut u16, values: __m512i) {
+        _mm256_storeu_si256(dst as *mut __m256i, _mm512_cvtepi32_epi16(values));
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn store_u8_avx512(dst: *mut u8, values: __m512i) {
+        _mm_storeu_si128(dst as *mut __m128i, _mm512_cvtepi32_epi8(values));
+    }
+
+    // Gathers always load 32 bits per lane. For narrower entries, every lane loads the 4 bytes
+    // that start at its entry, or that end with the last entry of the LUT so nothing past it is read,
+    // and then shifts its entry down and masks it.
+
+    /// `$load` gets a pointer to the next source pixels and zero-extends them to 32 bits.
+    /// `$scale` is the size of `$entry`, which `$store` narrows the 32-bit lanes to.
+    macro_rules! gather_avx2 {
+        ($name:ident, $type:ty, $entry:ty, $scale:literal, $store:ident, |$ptr:ident| $load:expr) => {
+            #[target_feature(enable = "avx2")]
+            pub unsafe fn $name(
+                src: &[$type],
+                lut: &[$entry],
+                shift: u8,
+                dst: &mut [$entry],
+            ) -> usize {
+                let per_load = 4 / $scale;
+                if lut.len() < per_load {
+                    return 0;
+                }
+                let n = src.len().min(dst.len()) / 8 * 8;
+                let shift = _mm_cvtsi32_si128(shift as i32);
+                let last = _mm256_set1_epi32(lut.len() as i32 - 1);
+                let last_load = _mm256_set1_epi32((lut.len() - per_load) as i32);
+                let bits = _mm256_set1_epi32($scale * 8);
+                let mask = _mm256_set1_epi32((u64::MAX >> (64 - $scale * 8)) as i32);
+                for i in (0..n).step_by(8) {
+                    let $ptr = src.as_ptr().add(i);
+                    let index = _mm256_min_epu32(_mm256_srl_epi32($load, shift), last);
+                    let start = _mm256_min_epu32(index, last_load);
+                    let words = _mm256_i32gather_epi32::<$scale>(lut.as_ptr() as *const i32, start);
+                    let offset = _mm256_mullo_epi32(_mm256_sub_epi32(index, start), bits);
+                    let values = _mm256_and_si256(_mm256_srlv_epi32(words, offset), mask);
+                    $store(dst.as_mut_ptr().add(i), values);
+                }
+                n
+            }
+        };
+    }
+
+    macro_rules! gather_avx512 {
+        ($name:ident, $type:ty, $entry:ty, $scale:literal, $store:ident, |$ptr:ident| $load:expr) => {
+            #[target_feature(enable = "avx512f")]
+            pub unsafe fn $name(
+                src: &[$type],
+                lut: &[$entry],
+                shift: u8,
+                dst: &mut [$entry],
+            ) -> usize {
+                let per_load = 4 / $scale;
+                if lut.len() < per_load {
+                    return 0;
+                }
+                let n = src.len().min(dst.len()) / 16 * 16;
+                let shift = _mm_cvtsi32_si128(shift as i32);
+                let last = _mm512_set1_epi32(lut.len() as i32 - 1);
+                let last_load = _mm512_set1_epi32((lut.len() - per_load) as i32);
+                let bits = _mm512_set1_epi32($scale * 8);
+                let mask = _mm512_set1_epi32((u64::MAX >> (64 - $scale * 8)) as i32);
+                for i in (0..n).step_by(16) {
+                    let $ptr = src.as_ptr().add(i);
+                    let index = _mm512_min_epu32(_mm512_srl_epi32($load, shift), last);
+                    let start = _mm512_min_epu32(index, last_load);
+                    let words = _mm512_i32gather_epi32::<$scale>(start, lut.as_ptr() as *const i32);
+                    let offset = _mm512_mullo_epi32(_mm512_sub_epi32(index, start), bits);
+                    let values = _mm512_and_si512(_mm512_srlv_epi32(words, offset), mask);
+                    $store(dst.as_mut_ptr().add(i), values);
+                }
+                n
+            }
+        };
+    }
+
+    /// Generates the kernels for one index type and every entry type.
+    macro_rules! gather_kernels {
+        ($type:ty, [$($name:ident, $entry:ty, $scale:literal, $store:ident);*],
+         |$ptr:ident| $load:expr) => {
+            $(gather_avx2!($name, $type, $entry, $scale, $store, |$ptr| $load);)*
+        };
+    }
+
+    macro_rules! gather_kernels_avx512 {
+        ($type:ty, [$($name:ident, $entry:ty, $scale:literal, $store:ident);*],
+         |$ptr:ident| $load:expr) => {
+            $(gather_avx512!($name, $type, $entry, $scale, $store, |$ptr| $load);)*
+        };
+    }
+
+    gather_kernels!(u8, [
+        gather_u8_u32_avx2, u32, 4, store_u32_avx2;
+        gather_u8_u16_avx2, u16, 2, store_u16_avx2;
+        gather_u8_u8_avx2, u8, 1, store_u8_avx2
+    ], |ptr| _mm256_cvtepu8_epi32(_mm_loadl_epi64(ptr as *const __m128i)));
+    gather_kernels!(u16, [
+        gather_u16_u32_avx2, u32, 4, store_u32_avx2;
+        gather_u16_u16_avx2, u16, 2, store_u16_avx2;
+        gather_u16_u8_avx2, u8, 1, store_u8_avx2
+    ], |ptr| _mm256_cvtepu16_epi32(_mm_loadu_si128(ptr as *const __m128i)));
+    gather_kernels!(u32, [
+        gather_u32_u32_avx2, u32, 4, store_u32_avx2;
+        gather_u32_u16_avx2, u16, 2, store_u16_avx2;
+        gather_u32_u8_avx2, u8, 1, store_u8_avx2
+    ], |ptr| _mm256_loadu_si256(ptr as *const __m256i));
+    gather_kernels_avx512!(u8, [
+        gather_u8_u32_avx512, u32, 4, store_u32_avx512;
+        gather_u8_u16_avx512, u16, 2, store_u16_avx512;
+        gather_u8_u8_avx512, u8, 1, store_u8_avx512
+    ], |ptr| _mm512_cvtepu8_epi32(_mm_loadu_si128(ptr as *const __m128i)));
+    gather_kernels_avx512!(u16, [
+        gather_u16_u32_avx512, u32, 4, store_u32_avx512;
+        gather_u16_u16_avx512, u16, 2, store_u16_avx512;
+        gather_u16_u8_avx512, u8, 1, store_u8_avx512
+    ], |ptr| _mm512_cvtepu16_epi32(_mm256_loadu_si256(ptr as *const __m256i)));
+    gather_kernels_avx512!(u32, [
+        gather_u32_u32_avx512, u32, 4, store_u32_avx512;
+        gather_u32_u16_avx512, u16, 2, store_u16_avx512;
+        gather_u32_u8_avx512, u8, 1, store_u8_avx512
+    ], |ptr| _mm512_loadu_si512(ptr as *const __m512i));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// All levels that can be tested on this machine.
+    fn levels() -> Vec<SimdLevel> {
+        [
+            SimdLevel::Scalar,
+            SimdLevel::Sse2,
+            SimdLevel::Avx2,
+            SimdLevel::Avx512,
+        ]
+        .iter()
+        .copied()
+        .filter(|&level| level <= SimdLevel::detect())
+        .collect()
+    }
+
+    #[test]
+    fn test_mask_base() {
+        // Not a multiple of any vector width, so the scalar remainder is tested as well
+        let src: Vec<f32> = (0..1001).map(|i| i as f32 / 800.0 - 0.1).collect();
+        for &clamp in &[false, true] {
+            let mut expected = vec![0.0; src.len()];
+            mask_base(SimdLevel::Scalar, &src, &mut expected, clamp);
+            for level in levels() {
+                let mut dst = vec![0.0; src.len()];
+                mask_base(level, &src, &mut dst, clamp);
+                for ((&x, &value), &exp) in src.iter().zip(&dst).zip(&expected) {
+                    assert!(
+                        (value - exp).abs() < 0.00001,
+                        "{:?} was wrong at {}, expected {}, got {}",
+                        level,
+                        x,
+                        exp,
+                        value
+                    );
+                }
+            }
+        }
+        let mut dst = [0.0];
+        mask_base(SimdLevel::Scalar, &[1.5], &mut dst, true);
+        assert_eq!(dst[0], get_mask_base(1.0).max(0.0));
+        // The 67 values just below white, where the polynomial is close to 0
+        let below_white: Vec<f32> = (1..=67)
+            .map(|i| f32::from_bits(1.0f32.to_bits() - i))
+            .collect();
+        for level in levels() {
+            for &clamp in &[false, true] {
+                let mut dst = vec![0.0; below_white.len()];
+                mask_base(level, &below_white, &mut dst, clamp);
+                for (&x, &value) in below_white.iter().zip(&dst) {
+                    assert!(
+                        (0.0..0.00001).contains(&value),
+                        "{:?} was wrong at {}, got {}",
+                        level,
+                        x,
+                        value
+                    );
+                }
+                mask_value_fast(level, &below_white, &mut dst, clamp, 0.5);
+                assert!(dst.iter().all(|v| !v.is_nan()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_fast_pow() {
+        use crate::grain::Rng;
+        use crate::mask::{calc_luma_scaling, get_mask_value, FLOAT_RANGE};
+
+        let check = |x: f32, luma_scaling: f32, expected: f32, value: f32| {
+            assert!(
+                (value - expected).abs() <= FAST_POW_MAX_ERROR,
+                "Mask was wrong at {} with luma scaling {}, expected {}, got {}",
+                x,
+                luma_scaling,
+                expected,
+                value
+            );
+        };
+        let luma_scalings: Vec<f32> = [0.0, 0.01, 0.2, 0.5, 0.8, 1.0]
+            .iter()
+            .flat_map(|&avg| [1.0, 10.0, 100.0].map(|ls| calc_luma_scaling(avg, ls)))
+            .collect();
+        // Dense samples, including a few outside of the valid range that have to be clamped
+        let mut rng = Rng::new(0, 0);
+        let random: Vec<f32> = (0..100_003)
+            .map(|_| (rng.next_gaussian() * 0.4 + 0.5).clamp(-0.1, 1.1))
+            .collect();
+        for &luma_scaling in &luma_scalings {
+            for &x in FLOAT_RANGE.iter() {
+                let value = fast_pow(get_mask_base(x), luma_scaling);
+                check(x, luma_scaling, get_mask_value(x, luma_scaling), value);
+            }
+            for level in levels() {
+                // Compared with the base from the same kernel, because the polynomial
+                // is rounded differently without FMA, which matters where it is close to 0.
+                for (&clamp, src) in [false, true].iter().zip(&[&FLOAT_RANGE[..], &random]) {
+                    let mut base = vec![0.0; src.len()];
+                    mask_base(level, src, &mut base, clamp);
+                    let mut dst = vec![0.0; src.len()];
+                    mask_value_fast(level, src, &mut dst, clamp, luma_scaling);
+                    for ((&x, &base), &value) in src.iter().zip(&base).zip(&dst) {
+                        check(x, luma_scaling, base.powf(luma_scaling), value);
+                    }
+                }
+            }
+        }
+        assert_eq!(fast_pow(0.0, 2.0), 0.0);
+        assert_eq!(fast_pow(0.0, 0.0), 1.0);
+        assert_eq!(fast_pow(1.0, 5.0), 1.0);
+    }
+
+    #[test]
+    fn test_gather() {
+        let lut: Vec<u32> = (0..1024).map(|i| i * 3 + 1).collect();
+        let src: Vec<u16> = (0..1000).map(|i| (i * 37 % 1024) as u16).collect();
+        let wide: Vec<u32> = src.iter().map(|&i| (i as u32) << 4).collect();
+        let bytes: Vec<u8> = src.iter().map(|&i| i as u8).collect();
+        for level in levels() {
+            let mut dst = vec![0; src.len()];
+            gather(level, &src, &lut, 0, &mut dst);
+            assert!(src.iter().zip(&dst).all(|(&i, &v)| v == lut[i as usize]));
+            let mut dst = vec![0; src.len()];
+            gather(level, &wide, &lut, 4, &mut dst);
+            assert!(src.iter().zip(&dst).all(|(&i, &v)| v == lut[i as usize]));
+            let mut dst = vec![0; src.len()];
+            gather(level, &bytes, &lut, 0, &mut dst);
+            assert!(bytes.iter().zip(&dst).all(|(&i, &v)| v == lut[i as usize]));
+
+            // Narrower entries, with the last ones in the LUT included
+            let lut16: Vec<u16> = lut.iter().map(|&v| (v * 61) as u16).collect();
+            let lut8: Vec<u8> = lut.iter().map(|&v| (v * 7) as u8).collect();
+            let ends: Vec<u16> = (0..67).map(|i| 1023 - (i % 5) as u16).collect();
+            for src in &[&src, &ends] {
+                let mut dst = vec![0; src.len()];
+                gather(level, src, &lut16, 0, &mut dst);
+                assert!(src.iter().zip(&dst).all(|(&i, &v)| v == lut16[i as usize]));
+                let mut dst = vec![0; src.len()];
+                gather(level, src, &lut8, 0, &mut dst);
+                assert!(src.iter().zip(&dst).all(|(&i, &v)| v == lut8[i as usize]));
+            }
+            let lut8_full: Vec<u8> = (0..=255).map(|i: u8| i.wrapping_mul(13)).collect();
+            let mut dst = vec![0; bytes.len()];
+            gather(level, &bytes, &lut8_full, 0, &mut dst);
+            assert!(bytes
+                .iter()
+                .zip(&dst)
+                .all(|(&i, &v)| v == lut8_full[i as usize]));
+            let mut dst = vec![0; wide.len()];
+            gather(level, &wide, &lut16, 4, &mut dst);
+            assert!(src.iter().zip(&dst).all(|(&i, &v)| v == lut16[i as usize]));
+
+            let float_lut: Vec<f32> = lut.iter().map(|&v| v as f32 / 3.0).collect();
+            let mut dst = vec![0.0; src.len()];
+            gather_f32(level, &src, &float_lut, 0, &mut dst);
+            assert!(src
+                .iter()
+                .zip(&dst)
+                .all(|(&i, &v)| v == float_lut[i as usize]));
+
+            // Indices past the end of the LUT (e.g. 10-bit input above 1023) are clamped,
+            // both in the vectorized part and the remainder
+            let past: Vec<u16> = (0..67).map(|i| 1020 + i as u16 * 3).collect();
+            let clamped = |i: u16| (i as usize).min(1023);
+            let mut dst = vec![0; past.len()];
+            gather(level, &past, &lut, 0, &mut dst);
+            assert!(past.iter().zip(&dst).all(|(&i, &v)| v == lut[clamped(i)]));
+            let mut dst = vec![0; past.len()];
+            gather(level, &past, &lut16, 0, &mut dst);
+            assert!(past.iter().zip(&dst).all(|(&i, &v)| v == lut16[clamped(i)]));
+            let mut dst = vec![0; past.len()];
+            gather(level, &past, &lut8, 0, &mut dst);
+            assert!(past.iter().zip(&dst).all(|(&i, &v)| v == lut8[clamped(i)]));
+            let mut dst = vec![0.0; past.len()];
+            gather_f32(level, &past, &float_lut, 0, &mut dst);
+            assert!(past
+                .iter()
+                .zip(&dst)
+                .all(|(&i, &v)| v == float_lut[clamped(i)]));
+        }
+    }
+}
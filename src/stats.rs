@@ -0,0 +1,518 @@
+use super::PLUGIN_NAME;
+use failure::Error;
+use half::f16;
+use std::ops::Range;
+use vapoursynth::prelude::*;
+
+/// Number of pixels that are excluded from the stats on each side of the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Crop {
+    pub left: usize,
+    pub right: usize,
+    pub top: usize,
+    pub bottom: usize,
+}
+
+impl Crop {
+    pub fn is_empty(self) -> bool {
+        self == Crop::default()
+    }
+
+    /// The remaining columns and rows of a plane with the given size.
+    pub fn region(
+        self,
+        width: usize,
+        height: usize,
+    ) -> Result<(Range<usize>, Range<usize>), Error> {
+        if self.left + self.right >= width || self.top + self.bottom >= height {
+            bail!(
+                "{}: cropping {:?} would leave nothing of a {}x{} frame",
+                PLUGIN_NAME,
+                self,
+                width,
+                height
+            );
+        }
+        Ok((
+            self.left..width - self.right,
+            self.top..height - self.bottom,
+        ))
+    }
+
+    /// Crops as much as either of the two on every side.
+    pub fn max(self, other: Crop) -> Crop {
+        Crop {
+            left: self.left.max(other.left),
+            right: self.right.max(other.right),
+            top: self.top.max(other.top),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    /// Adds detected borders to this crop of a plane with the given size.
+    /// Each of them leaves something of the plane, but together they might not,
+    /// and then only this crop is used.
+    pub fn with_borders(self, borders: Crop, width: usize, height: usize) -> Crop {
+        let combined = self.max(borders);
+        if combined.region(width, height).is_ok() {
+            combined
+        } else {
+            self
+        }
+    }
+}
+
+/// Borders can cover at most this fraction of the plane on each side,
+/// which is enough for 2.39:1 in 4:3 (about 22% on the top and bottom).
+pub const MAX_BORDER: f32 = 0.25;
+
+/// Finds black borders (letterbox or pillarbox) in a plane, given by its rows.
+/// A row or column counts as black if none of its pixels are brighter than `threshold`,
+/// and scanning it stops at the first pixel that is.
+/// Entirely black frames are not cropped at all.
+///
+/// In dark scenes, the black rows and columns can reach into the picture,
+/// so the borders are limited to what a letterbox or pillarbox would look like:
+/// opposite borders are cropped by the same (smaller) amount, and by at most `MAX_BORDER` of the plane.
+pub fn detect_borders<'a, T: Copy + Into<f64> + 'a>(
+    width: usize,
+    height: usize,
+    threshold: f64,
+    row: impl Fn(usize) -> &'a [T],
+) -> Crop {
+    let black = |value: T| value.into() <= threshold;
+    let black_row = |y: usize| row(y).iter().all(|&v| black(v));
+    let top = (0..height).take_while(|&y| black_row(y)).count();
+    if top == height {
+        return Crop::default();
+    }
+    let bottom = (0..height).rev().take_while(|&y| black_row(y)).count();
+    // Only check the columns between the horizontal borders
+    let black_column = |x: usize| (top..height - bottom).all(|y| black(row(y)[x]));
+    let left = (0..width).take_while(|&x| black_column(x)).count();
+    let right = (0..width).rev().take_while(|&x| black_column(x)).count();
+    let vertical = top.min(bottom).min((height as f32 * MAX_BORDER) as usize);
+    let horizontal = left.min(right).min((width as f32 * MAX_BORDER) as usize);
+    Crop {
+        left: horizontal,
+        right: horizontal,
+        top: vertical,
+        bottom: vertical,
+    }
+}
+
+/// Finds black borders in the luma plane of a frame. `threshold` is normalized to 0-1.
+pub fn frame_borders(frame: &Frame, threshold: f32) -> Crop {
+    let format = frame.format();
+    let (width, height) = (frame.width(0), frame.height(0));
+    let threshold = match format.sample_type() {
+        SampleType::Integer => threshold as f64 * ((1u64 << format.bits_per_sample()) - 1) as f64,
+        SampleType::Float => threshold as f64,
+    };
+    match (format.sample_type(), format.bytes_per_sample()) {
+        (SampleType::Integer, 1) => {
+            detect_borders(width, height, threshold, |y| frame.plane_row::<u8>(0, y))
+        }
+        (SampleType::Integer, 2) => {
+            detect_borders(width, height, threshold, |y| frame.plane_row::<u16>(0, y))
+        }
+        (SampleType::Integer, _) => {
+            detect_borders(width, height, threshold, |y| frame.plane_row::<u32>(0, y))
+        }
+        (SampleType::Float, 2) => {
+            detect_borders(width, height, threshold, |y| frame.plane_row::<f16>(0, y))
+        }
+        (SampleType::Float, _) => {
+            detect_borders(width, height, threshold, |y| frame.plane_row::<f32>(0, y))
+        }
+    }
+}
+
+/// Luma statistics of a frame in the same form that std.PlaneStats provides them,
+/// i.e. normalized to 0-1 for integer input and unscaled for float input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlaneStats {
+    pub average: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl PlaneStats {
+    /// Reads the stats from the frame props if std.PlaneStats (or something else) has set them.
+    pub fn from_props(props: &Map, sample_type: SampleType) -> Option<Self> {
+        let average = props.get::<f64>("PlaneStatsAverage").ok()? as f32;
+        match sample_type {
+            // Integer input can’t leave the valid range, so we only need the average.
+            SampleType::Integer => Some(PlaneStats {
+                average,
+                min: 0.0,
+                max: 1.0,
+            }),
+            SampleType::Float => Some(PlaneStats {
+                average,
+                min: props.get::<f64>("PlaneStatsMin").ok()? as f32,
+                max: props.get::<f64>("PlaneStatsMax").ok()? as f32,
+            }),
+        }
+    }
+
+    /// Uses the stats from the frame props if they exist and calculates them otherwise.
+    pub fn get(frame: &Frame) -> Result<Self, Error> {
+        match Self::from_props(&frame.props(), frame.format().sample_type()) {
+            Some(stats) => Ok(stats),
+            None => Self::from_frame(frame),
+        }
+    }
+
+    /// Calculates the stats from the luma plane of the given frame.
+    pub fn from_frame(frame: &Frame) -> Result<Self, Error> {
+        Self::from_region(frame, Crop::default())
+    }
+
+    /// Calculates the stats from the luma plane of the given frame without the cropped borders.
+    pub fn from_region(frame: &Frame, crop: Crop) -> Result<Self, Error> {
+        let format = frame.format();
+        let depth = format.bits_per_sample();
+        let peak = ((1u64 << depth) - 1) as f64;
+        let (columns, rows) = crop.region(frame.width(0), frame.height(0))?;
+        Ok(match (format.sample_type(), format.bytes_per_sample()) {
+            (SampleType::Integer, 1) => accumulate::<u8>(frame, columns, rows, peak),
+            (SampleType::Integer, 2) => accumulate::<u16>(frame, columns, rows, peak),
+            (SampleType::Integer, 4) => accumulate::<u32>(frame, columns, rows, peak),
+            (SampleType::Float, 2) => accumulate::<f16>(frame, columns, rows, 1.0),
+            (SampleType::Float, 4) => accumulate::<f32>(frame, columns, rows, 1.0),
+            _ => bail!(format!(
+                "{}: cannot compute plane stats for {}",
+                PLUGIN_NAME,
+                format.name()
+            )),
+        })
+    }
+}
+
+/// Pixels are counted in a histogram with this many bits before a nonlinear function is averaged over them.
+/// Deeper integer input is shifted down, float input is clamped to 0-1 and rounded.
+const HISTOGRAM_DEPTH: u8 = 16;
+
+/// The average of `map` over the (normalized) pixels of the luma plane without the cropped borders.
+/// Used for HDR input, where the frame average has to be taken after converting every pixel.
+/// `map` is only evaluated once per bin of a histogram of the plane,
+/// with the bins normalized like the LUT input of `Mask` (i.e. 0-1 over at most 16 bits).
+pub fn mapped_average(frame: &Frame, crop: Crop, map: impl Fn(f32) -> f32) -> Result<f32, Error> {
+    let format = frame.format();
+    let (columns, rows) = crop.region(frame.width(0), frame.height(0))?;
+    let depth = match format.sample_type() {
+        SampleType::Integer => format.bits_per_sample(),
+        SampleType::Float => HISTOGRAM_DEPTH,
+    };
+    let shift = depth.saturating_sub(HISTOGRAM_DEPTH);
+    let mut bins = vec![0u64; 1 << depth.min(HISTOGRAM_DEPTH)];
+    let float_bin = |x: f32| (x.clamp(0.0, 1.0) * 65535.0).round() as usize;
+    match (format.sample_type(), format.bytes_per_sample()) {
+        (SampleType::Integer, 1) => {
+            histogram::<u8>(frame, columns, rows, &mut bins, |x| x as usize)
+        }
+        (SampleType::Integer, 2) => {
+            histogram::<u16>(frame, columns, rows, &mut bins, |x| x as usize)
+        }
+        (SampleType::Integer, _) => {
+            histogram::<u32>(frame, columns, rows, &mut bins, |x| (x >> shift) as usize)
+        }
+        (SampleType::Float, 2) => {
+            histogram::<f16>(frame, columns, rows, &mut bins, |x| float_bin(x.to_f32()))
+        }
+        (SampleType::Float, _) => histogram::<f32>(frame, columns, rows, &mut bins, float_bin),
+    }
+    Ok(histogram_average(&bins, map))
+}
+
+fn histogram<T: Component + Copy>(
+    frame: &Frame,
+    columns: Range<usize>,
+    rows: Range<usize>,
+    bins: &mut [u64],
+    bin: impl Fn(T) -> usize,
+) {
+    for row in rows {
+        for &pixel in &frame.plane_row::<T>(0, row)[columns.clone()] {
+            bins[bin(pixel)] += 1;
+        }
+    }
+}
+
+/// The average of `map` over a histogram whose bins stand for the values 0-1.
+fn histogram_average(bins: &[u64], map: impl Fn(f32) -> f32) -> f32 {
+    let max = (bins.len() - 1) as f32;
+    let (sum, count) = bins
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .fold((0.0, 0), |(sum, total), (i, &count)| {
+            (
+                sum + map(i as f32 / max) as f64 * count as f64,
+                total + count,
+            )
+        });
+    if count == 0 {
+        return 0.0;
+    }
+    (sum / count as f64) as f32
+}
+
+/// Weighted average of the frame averages around the current frame.
+/// `averages` contains pairs of (distance to the current frame, frame average),
+/// and each frame is weighted with `decay` to the power of its distance.
+pub fn temporal_average(averages: &[(usize, f32)], decay: f32) -> f32 {
+    let (sum, weights) =
+        averages
+            .iter()
+            .fold((0.0, 0.0), |(sum, weights), &(distance, average)| {
+                let weight = decay.powi(distance as i32);
+                (sum + average * weight, weights + weight)
+            });
+    sum / weights
+}
+
+/// Finds the first and last frame of the scene that contains `current`.
+/// `scene_changes` contains the (`_SceneChangePrev`, `_SceneChangeNext`) props of consecutive frames.
+pub fn scene_bounds(scene_changes: &[(bool, bool)], current: usize) -> (usize, usize) {
+    let mut start = current;
+    while start > 0 && !scene_changes[start].0 && !scene_changes[start - 1].1 {
+        start -= 1;
+    }
+    let mut end = current;
+    while end + 1 < scene_changes.len() && !scene_changes[end].1 && !scene_changes[end + 1].0 {
+        end += 1;
+    }
+    (start, end)
+}
+
+fn accumulate<T: Component + Copy + Into<f64>>(
+    frame: &Frame,
+    columns: Range<usize>,
+    rows: Range<usize>,
+    peak: f64,
+) -> PlaneStats {
+    let mut acc = Accumulator::default();
+    for row in rows {
+        acc.add_row(&frame.plane_row::<T>(0, row)[columns.clone()]);
+    }
+    acc.finish(peak)
+}
+
+struct Accumulator {
+    sum: f64,
+    min: f64,
+    max: f64,
+    count: usize,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Accumulator {
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            count: 0,
+        }
+    }
+}
+
+impl Accumulator {
+    fn add_row<T: Copy + Into<f64>>(&mut self, row: &[T]) {
+        for &pixel in row {
+            let value = pixel.into();
+            self.sum += value;
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += row.len();
+    }
+
+    fn finish(self, peak: f64) -> PlaneStats {
+        if self.count == 0 {
+            return PlaneStats {
+                average: 0.0,
+                min: 0.0,
+                max: 0.0,
+            };
+        }
+        PlaneStats {
+            average: (self.sum / self.count as f64 / peak) as f32,
+            min: (self.min / peak) as f32,
+            max: (self.max / peak) as f32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accumulator() {
+        let mut acc = Accumulator::default();
+        acc.add_row(&[0u16, 1023]);
+        acc.add_row(&[512u16, 512]);
+        let stats = acc.finish(1023.0);
+        assert_eq!(stats.min, 0.0);
+        assert_eq!(stats.max, 1.0);
+        assert!((stats.average - 2047.0 / 4092.0).abs() < 0.00001);
+
+        let mut acc = Accumulator::default();
+        acc.add_row(&[-0.5f32, 0.25, 1.5]);
+        let stats = acc.finish(1.0);
+        assert_eq!(stats.min, -0.5);
+        assert_eq!(stats.max, 1.5);
+        assert!((stats.average - 0.4166667).abs() < 0.00001);
+
+        let mut acc = Accumulator::default();
+        acc.add_row(&[f16::from_f32(0.25), f16::from_f32(0.75)]);
+        let stats = acc.finish(1.0);
+        assert_eq!((stats.min, stats.max, stats.average), (0.25, 0.75, 0.5));
+    }
+
+    #[test]
+    fn test_histogram_average() {
+        use crate::luma::Transfer;
+
+        let mut bins = vec![0u64; 1024];
+        bins[0] = 1;
+        bins[1023] = 3;
+        assert!((histogram_average(&bins, |x| x) - 0.75).abs() < 0.000001);
+        assert!((histogram_average(&bins, |x| 1.0 - x) - 0.25).abs() < 0.000001);
+        assert_eq!(histogram_average(&[0u64; 256], |x| x), 0.0);
+
+        // Half of a PQ frame at 1000 nits and the other half at 10 nits. The first is clipped to SDR white.
+        let mut bins = vec![0u64; 1 << 16];
+        bins[(0.7518 * 65535.0) as usize] = 100;
+        bins[(0.3076 * 65535.0) as usize] = 100;
+        let average = histogram_average(&bins, |x| Transfer::Pq.to_sdr(x));
+        let expected = (1.0 + (10.0f32 / 203.0).powf(1.0 / 2.4)) / 2.0;
+        assert!((average - expected).abs() < 0.01, "{}", average);
+        // Converting the average code value instead would give a much brighter frame.
+        assert!(Transfer::Pq.to_sdr((0.7518 + 0.3076) / 2.0) > 0.8);
+        assert!(average < 0.7);
+    }
+
+    #[test]
+    fn test_crop_region() {
+        assert!(Crop::default().is_empty());
+        assert_eq!(Crop::default().region(4, 3).unwrap(), (0..4, 0..3));
+        let crop = Crop {
+            left: 1,
+            right: 2,
+            top: 0,
+            bottom: 1,
+        };
+        assert!(!crop.is_empty());
+        assert_eq!(crop.region(4, 3).unwrap(), (1..2, 0..2));
+        assert!(crop.region(3, 3).is_err());
+        assert!(crop.region(4, 1).is_err());
+    }
+
+    #[test]
+    fn test_detect_borders() {
+        // 8x8 with black rows on the top and bottom, and black columns on the left and right
+        let (width, height) = (8, 8);
+        let mut values = vec![0.06; width * height];
+        let mut fill = |xs: std::ops::Range<usize>, ys: std::ops::Range<usize>, value| {
+            for y in ys {
+                for x in xs.clone() {
+                    values[y * width + x] = value;
+                }
+            }
+        };
+        fill(1..7, 1..6, 0.5);
+        let borders = |values: &[f64], threshold| {
+            detect_borders(width, height, threshold, |y| {
+                &values[y * width..(y + 1) * width]
+            })
+        };
+        // The bottom border is thicker than the top one, only as much as the top is cropped
+        let symmetric = Crop {
+            left: 1,
+            right: 1,
+            top: 1,
+            bottom: 1,
+        };
+        assert_eq!(borders(&values, 0.08), symmetric);
+        assert_eq!(borders(&values, 0.05), Crop::default());
+        assert_eq!(
+            detect_borders(width, height, 0.08, |_| &[0u8; 8][..]),
+            Crop::default()
+        );
+        // A dark frame with a single bright spot isn’t cropped down to that spot
+        let mut dark = vec![0.0; width * height];
+        dark[3 * width + 4] = 0.5;
+        assert_eq!(
+            borders(&dark, 0.08),
+            Crop {
+                left: 2,
+                right: 2,
+                top: 2,
+                bottom: 2,
+            }
+        );
+        dark[3 * width + 4] = 0.0;
+        dark[3 * width + 7] = 0.5;
+        assert_eq!(
+            borders(&dark, 0.08),
+            Crop {
+                left: 0,
+                right: 0,
+                top: 2,
+                bottom: 2,
+            }
+        );
+
+        let manual = Crop {
+            left: 0,
+            right: 3,
+            top: 2,
+            bottom: 0,
+        };
+        assert_eq!(
+            symmetric.max(manual),
+            Crop {
+                left: 1,
+                right: 3,
+                top: 2,
+                bottom: 1,
+            }
+        );
+        assert_eq!(
+            manual.with_borders(symmetric, width, height),
+            symmetric.max(manual)
+        );
+        // Together, they would crop the entire frame
+        let manual = Crop {
+            left: 7,
+            ..Crop::default()
+        };
+        assert_eq!(manual.with_borders(symmetric, width, height), manual);
+    }
+
+    #[test]
+    fn test_temporal_average() {
+        let averages = [(1, 0.2), (0, 0.5), (1, 0.8), (2, 0.9)];
+        assert!((temporal_average(&averages, 1.0) - 0.6).abs() < 0.00001);
+        // weights 0.5, 1, 0.5, 0.25
+        assert!((temporal_average(&averages, 0.5) - 1.225 / 2.25).abs() < 0.00001);
+        assert_eq!(temporal_average(&[(0, 0.3)], 0.5), 0.3);
+    }
+
+    #[test]
+    fn test_scene_bounds() {
+        let none = (false, false);
+        assert_eq!(scene_bounds(&[none; 5], 2), (0, 4));
+        // cut between frames 0 and 1, signaled by frame 1
+        let changes = [none, (true, false), none, none, none];
+        assert_eq!(scene_bounds(&changes, 2), (1, 4));
+        assert_eq!(scene_bounds(&changes, 0), (0, 0));
+        // cut between frames 2 and 3, signaled by frame 2
+        let changes = [none, none, (false, true), none, none];
+        assert_eq!(scene_bounds(&changes, 2), (0, 2));
+        assert_eq!(scene_bounds(&changes, 3), (3, 4));
+    }
+}